@@ -0,0 +1,67 @@
+/*
+ * Adler-32 checksum, used by the zlib wrapper format (RFC 1950) in place of
+ * the CRC-32 of gzip.
+ */
+
+/* Largest prime smaller than 65536 */
+const ADLER32_MOD: u32 = 65521;
+
+/*
+ * Largest number of bytes that can be summed before reducing modulo
+ * ADLER32_MOD without overflowing 32 bits:
+ * 255 * n * (n + 1) / 2 + (n + 1) * (ADLER32_MOD - 1) <= 2^32 - 1
+ */
+const ADLER32_NMAX: usize = 5552;
+
+#[derive(Clone, Debug)]
+pub struct Adler32 {
+    s1: u32,
+    s2: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Self::new_with_initial(1)
+    }
+
+    /* Continue a checksum computed over previous data */
+    pub fn new_with_initial(adler: u32) -> Self {
+        Self {
+            s1: adler & 0xFFFF,
+            s2: adler >> 16,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut s1 = self.s1;
+        let mut s2 = self.s2;
+
+        for chunk in data.chunks(ADLER32_NMAX) {
+            for &byte in chunk {
+                s1 += byte as u32;
+                s2 += s1;
+            }
+            s1 %= ADLER32_MOD;
+            s2 %= ADLER32_MOD;
+        }
+
+        self.s1 = s1;
+        self.s2 = s2;
+    }
+
+    pub fn finalize(&self) -> u32 {
+        (self.s2 << 16) | self.s1
+    }
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut hasher = Adler32::new();
+    hasher.update(data);
+    hasher.finalize()
+}
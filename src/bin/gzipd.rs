@@ -1,29 +1,163 @@
-use streaming_libdeflate_rs::decompress_file_buffered;
 use std::fs::File;
-use std::io::{Write};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use streaming_libdeflate_rs::{decompress_file_buffered, FileDecompressStats, LibdeflateError};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct GzipParams {
-    input: PathBuf,
+    input: Vec<PathBuf>,
     #[structopt(short)]
     simulate: bool,
+    #[structopt(short, long)]
+    verbose: bool,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        help = "Also decompress the files listed in FILE, one per line"
+    )]
+    from_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "DIR",
+        help = "Write the decompressed files to DIR instead of next to the inputs"
+    )]
+    output_dir: Option<PathBuf>,
+    #[cfg(feature = "serde_json")]
+    #[structopt(long, help = "Print the progress of each file as JSON lines on stdout")]
+    json_progress: bool,
 }
 
-fn main() {
-    let params: GzipParams = GzipParams::from_args();
+enum Progress<'a> {
+    Started,
+    Done(&'a FileDecompressStats),
+    Error(String),
+}
+
+#[cfg(feature = "serde_json")]
+fn print_json_progress(file: &Path, progress: &Progress) {
+    let file = file.to_string_lossy();
+    let line = match progress {
+        Progress::Started => serde_json::json!({ "file": file, "status": "started" }),
+        Progress::Done(stats) => serde_json::json!({
+            "file": file,
+            "status": "done",
+            "compressed_bytes": stats.compressed_bytes,
+            "decompressed_bytes": stats.decompressed_bytes,
+            "duration_ms": stats.duration.as_millis() as u64,
+        }),
+        Progress::Error(error) => {
+            serde_json::json!({ "file": file, "status": "error", "error": error })
+        }
+    };
+    println!("{}", line);
+}
+
+fn report(params: &GzipParams, file: &Path, progress: Progress) {
+    #[cfg(feature = "serde_json")]
+    if params.json_progress {
+        print_json_progress(file, &progress);
+        return;
+    }
+
+    match progress {
+        Progress::Started => {}
+        Progress::Done(stats) => {
+            if params.verbose {
+                eprintln!(
+                    "{}: members: {} compressed: {} decompressed: {} ratio: {:.2} time: {:.2?} ({:.2} MB/s)",
+                    file.display(),
+                    stats.members_processed,
+                    stats.compressed_bytes,
+                    stats.decompressed_bytes,
+                    stats.compression_ratio(),
+                    stats.duration,
+                    stats.throughput_mb_per_s
+                );
+            }
+        }
+        Progress::Error(error) => eprintln!("{}: {}", file.display(), error),
+    }
+}
+
+/* Like gzip, only inputs ending in .gz are decompressed, so that the output
+ * never replaces the input */
+fn output_path(params: &GzipParams, input: &Path) -> Result<PathBuf, String> {
+    if input.extension().map_or(true, |ext| ext != "gz") {
+        return Err("unknown suffix -- ignored".to_string());
+    }
+    let output = match &params.output_dir {
+        Some(dir) => dir.join(Path::new(input.file_name().unwrap_or_default()).with_extension("")),
+        None => input.with_extension(""),
+    };
+    if output == input {
+        return Err(format!(
+            "{}: output would overwrite the input",
+            output.display()
+        ));
+    }
+    Ok(output)
+}
+
+fn error_message(err: LibdeflateError) -> String {
+    match err {
+        LibdeflateError::ReadError(err) | LibdeflateError::WriteError(err) => err.to_string(),
+        err => format!("{:?}", err),
+    }
+}
 
+fn decompress(params: &GzipParams, input: &Path) -> Result<FileDecompressStats, String> {
     if params.simulate {
-        decompress_file_buffered(params.input, move |_| Ok(()), 1024 * 512).unwrap();
+        decompress_file_buffered(input, move |_| Ok(()), 1024 * 512).map_err(error_message)
     } else {
-        let mut write_file = File::create(&params.input.with_extension("")).unwrap();
+        let output = output_path(params, input)?;
+        let mut write_file =
+            File::create(&output).map_err(|err| format!("{}: {}", output.display(), err))?;
 
-        decompress_file_buffered(
-            params.input,
+        let result = decompress_file_buffered(
+            input,
             move |data| write_file.write_all(data).map_err(|_| ()),
             1024 * 512 * 1024,
         )
-        .unwrap();
-    };
+        .map_err(error_message);
+        /* Like gzip, don't leave an incomplete output, e.g. for a missing input */
+        if result.is_err() {
+            let _ = std::fs::remove_file(&output);
+        }
+        result
+    }
+}
+
+fn main() {
+    let params: GzipParams = GzipParams::from_args();
+
+    let mut inputs = params.input.clone();
+    if let Some(list) = &params.from_file {
+        let list = std::fs::read_to_string(list).unwrap_or_else(|err| {
+            eprintln!("{}: {}", list.display(), err);
+            exit(1);
+        });
+        inputs.extend(
+            list.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(PathBuf::from),
+        );
+    }
+
+    let mut failed = false;
+    for input in &inputs {
+        report(&params, input, Progress::Started);
+        match decompress(&params, input) {
+            Ok(stats) => report(&params, input, Progress::Done(&stats)),
+            Err(error) => {
+                failed = true;
+                report(&params, input, Progress::Error(error));
+            }
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
 }
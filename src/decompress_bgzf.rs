@@ -0,0 +1,129 @@
+/*
+ * BGZF (blocked gzip format), as used by BAM/VCF/tabix files.
+ *
+ * A BGZF file is a series of gzip members, each holding at most 64 KiB of
+ * decompressed data, whose header carries an extra subfield with SI1 = 'B',
+ * SI2 = 'C' storing the total size of the member minus 1.  The file ends with
+ * an empty member acting as an EOF marker.
+ */
+
+use crate::decompress_deflate::report_error;
+use crate::decompress_gzip::{finish_gzip_member, read_gzip_header, GzipHeader};
+use crate::decompress_utils::{check_input_error, deflate_decompress_stream};
+use crate::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
+use crate::streams::deflate_chunked_buffer_output::DeflateChunkedBufferOutput;
+use crate::{
+    libdeflate_alloc_decompressor, safety_check, DeflateInput, DeflateOutput,
+    LibdeflateDecompressor, LibdeflateError,
+};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+pub const BGZF_SI1: u8 = 66;
+pub const BGZF_SI2: u8 = 67;
+pub const BGZF_BSIZE_SLEN: u16 = 2;
+
+/* Maximum amount of decompressed data in a single BGZF member */
+pub const BGZF_MAX_BLOCK_SIZE: usize = 65536;
+
+const BGZF_INPUT_BUF_SIZE: usize = 1024 * 512;
+
+/* Large enough to hold a whole member without intermediate flushes */
+const BGZF_OUTPUT_BUF_SIZE: usize = BGZF_MAX_BLOCK_SIZE * 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BgzfBlockInfo {
+    /* The BSIZE field: total size of the member in bytes minus 1 */
+    pub block_size: u16,
+    pub uncompressed_size: u32,
+}
+
+impl BgzfBlockInfo {
+    /* The EOF marker is a member with no decompressed data */
+    pub fn is_eof_marker(&self) -> bool {
+        self.uncompressed_size == 0
+    }
+}
+
+/* Find the BSIZE value in the BC subfield of the gzip extra field */
+fn find_bsize(mut extra: &[u8]) -> Option<u16> {
+    while extra.len() >= 4 {
+        let slen = u16::from_le_bytes([extra[2], extra[3]]);
+        let payload = extra.get(4..4 + slen as usize)?;
+
+        if extra[0] == BGZF_SI1 && extra[1] == BGZF_SI2 && slen == BGZF_BSIZE_SLEN {
+            return Some(u16::from_le_bytes([payload[0], payload[1]]));
+        }
+        extra = &extra[4 + slen as usize..];
+    }
+    None
+}
+
+pub fn decompress_bgzf_member<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+) -> Result<BgzfBlockInfo, LibdeflateError> {
+    let result = decode_bgzf_member(d, in_stream, out_stream);
+    report_error(d, result)
+}
+
+fn decode_bgzf_member<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+) -> Result<BgzfBlockInfo, LibdeflateError> {
+    let mut header = GzipHeader::default();
+    read_gzip_header(in_stream, Some(&mut header), d.allow_reserved_flags)?;
+
+    let block_size = header.extra.as_deref().and_then(find_bsize);
+    safety_check!(block_size.is_some());
+
+    /* Compressed data  */
+    deflate_decompress_stream(d, in_stream, out_stream, |_| {})?;
+
+    let result = finish_gzip_member(in_stream, out_stream)?;
+    safety_check!(result.written <= BGZF_MAX_BLOCK_SIZE);
+
+    Ok(BgzfBlockInfo {
+        block_size: block_size.unwrap(),
+        uncompressed_size: result.written as u32,
+    })
+}
+
+/*
+ * Decompress a BGZF file, calling 'func' once for each non-empty member with
+ * its index in the file and its decompressed data.  Returns the number of
+ * members read, including the EOF marker.
+ */
+pub fn decompress_bgzf_file(
+    file: impl AsRef<Path>,
+    mut func: impl FnMut(usize, &[u8]) -> Result<(), ()>,
+) -> Result<usize, LibdeflateError> {
+    let mut read_file = File::open(file).map_err(LibdeflateError::ReadError)?;
+
+    let mut input_stream =
+        DeflateChunkedBufferInput::new(|buf| read_file.read(buf), BGZF_INPUT_BUF_SIZE);
+
+    let block_index = Cell::new(0);
+    let mut output_stream = DeflateChunkedBufferOutput::new(
+        |data| {
+            if data.is_empty() {
+                return Ok(());
+            }
+            func(block_index.get(), data)
+        },
+        BGZF_OUTPUT_BUF_SIZE,
+    );
+
+    let mut decompressor = libdeflate_alloc_decompressor();
+
+    while input_stream.ensure_length(1) {
+        decompress_bgzf_member(&mut decompressor, &mut input_stream, &mut output_stream)?;
+        block_index.set(block_index.get() + 1);
+    }
+    check_input_error(&mut input_stream)?;
+    Ok(block_index.get())
+}
@@ -0,0 +1,122 @@
+use crate::decompress_gzip::gzip_decompress;
+use crate::decompress_utils::check_input_error;
+use crate::libdeflate_alloc_decompressor;
+use crate::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
+use crate::streams::deflate_chunked_buffer_output::DeflateChunkedBufferOutput;
+use crate::{DeflateInput, LibdeflateError};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::thread::JoinHandle;
+
+/* Number of decompressed chunks that can be queued ahead of the consumer */
+const CHUNKS_QUEUE_SIZE: usize = 2;
+
+type ChunkResult = Result<Vec<u8>, LibdeflateError>;
+
+/*
+ * Pull-style decompression of a gzip file.  The decompressor runs on a worker
+ * thread that pushes each flushed chunk through a bounded channel, so at most
+ * CHUNKS_QUEUE_SIZE chunks are buffered ahead of the consumer.
+ *
+ * The output buffer also holds the window of the decoder, so each chunk is
+ * copied out of it; the consumer sends the chunks back once done with them,
+ * and the worker reuses their allocations.
+ */
+pub struct DecompressChunks {
+    receiver: Option<Receiver<ChunkResult>>,
+    recycler: Sender<Vec<u8>>,
+    current: Vec<u8>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl DecompressChunks {
+    pub fn new(file: impl AsRef<Path>, buf_size: usize) -> std::io::Result<Self> {
+        Ok(Self::from_reader(File::open(file)?, buf_size))
+    }
+
+    /* Like new(), decompressing the gzip data read from 'reader' */
+    pub fn from_reader(mut reader: impl Read + Send + 'static, buf_size: usize) -> Self {
+        let (sender, receiver) = sync_channel::<ChunkResult>(CHUNKS_QUEUE_SIZE);
+        let (recycler, recycled) = channel::<Vec<u8>>();
+
+        let worker = std::thread::spawn(move || {
+            let mut input_stream = DeflateChunkedBufferInput::new(|buf| reader.read(buf), buf_size);
+
+            let mut output_stream = DeflateChunkedBufferOutput::new(
+                |data| {
+                    if data.is_empty() {
+                        return Ok(());
+                    }
+                    let mut chunk = recycled.try_recv().unwrap_or_default();
+                    chunk.clear();
+                    chunk.extend_from_slice(data);
+                    /* Fails only if the consumer has been dropped */
+                    sender.send(Ok(chunk)).map_err(|_| ())
+                },
+                buf_size,
+            );
+
+            let mut decompressor = libdeflate_alloc_decompressor();
+
+            let mut decompress = || {
+                while input_stream.ensure_length(1) {
+                    gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?;
+                }
+                check_input_error(&mut input_stream)
+            };
+
+            if let Err(err) = decompress() {
+                let _ = sender.send(Err(err));
+            }
+        });
+
+        Self {
+            receiver: Some(receiver),
+            recycler,
+            current: Vec::new(),
+            worker: Some(worker),
+        }
+    }
+
+    /*
+     * Returns the next decompressed chunk.  The slice is valid until the next
+     * call; None is returned once the whole file has been decompressed or
+     * after an error has been reported.  A panic of the worker thread is
+     * reported as WorkerPanicked.
+     */
+    pub fn next_chunk(&mut self) -> Option<Result<&[u8], LibdeflateError>> {
+        let receiver = self.receiver.as_ref()?;
+        if self.current.capacity() > 0 {
+            /* Fails only if the worker has exited */
+            let _ = self.recycler.send(std::mem::take(&mut self.current));
+        }
+
+        match receiver.recv() {
+            Ok(Ok(chunk)) => {
+                self.current = chunk;
+                Some(Ok(&self.current))
+            }
+            Ok(Err(err)) => Some(Err(err)),
+            Err(_) => {
+                /* The worker dropped the sender, either by returning or by panicking */
+                self.receiver = None;
+                match self.worker.take()?.join() {
+                    Ok(()) => None,
+                    Err(_) => Some(Err(LibdeflateError::WorkerPanicked)),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DecompressChunks {
+    fn drop(&mut self) {
+        /* Dropping the receiver makes the pending send fail, stopping the worker */
+        self.receiver.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -41,15 +41,145 @@ use crate::decompress_utils::*;
 use crate::deflate_constants::*;
 use crate::{DeflateInput, DeflateOutput, LibdeflateDecompressor, LibdeflateError};
 use nightly_quirks::branch_pred::unlikely;
-use std::mem::size_of;
 
+pub use crate::decompress_utils::libdeflate_deflate_decompress;
+#[cfg(any(test, feature = "debug-info"))]
+pub use crate::decompress_utils::libdeflate_deflate_decompress_with_lens;
+pub use crate::deflate_constants::{
+    DEFLATE64_MAX_MATCH_LEN, DEFLATE64_MAX_WINDOW_SIZE, DEFLATE_NUM_PRECODE_SYMS,
+};
+
+/*
+ * The precode table is indexed by a full DEFLATE_MAX_PRE_CODEWORD_LEN bits
+ * wide lookup, so that reading the codeword lengths of a dynamic block never
+ * has to follow a subtable pointer.  The precode read loop relies on this, a
+ * smaller value would need subtable handling there first.
+ */
 pub const PRECODE_TABLEBITS: usize = 7;
+const_assert!(PRECODE_TABLEBITS == DEFLATE_MAX_PRE_CODEWORD_LEN);
 pub const LITLEN_TABLEBITS: usize = 10;
 pub const OFFSET_TABLEBITS: usize = 8;
 
+#[derive(Default)]
 pub struct OutStreamResult {
     pub written: usize,
     pub crc32: u32,
+    /* Compressed bytes of the member, including its header and trailer.
+     * Filled in by the gzip decoder, final_flush() leaves it to 0. */
+    pub consumed: usize,
+    /* Digest of the decompressed data, for outputs computing one */
+    pub digest: Option<Box<[u8]>>,
+    /* Blocks of each type in the member and the literals and matches decoded
+     * from the Huffman ones.  Filled in by the gzip decoder when built with
+     * the "stats" feature, 0 otherwise. */
+    pub dynamic_blocks: u32,
+    pub static_blocks: u32,
+    pub uncompressed_blocks: u32,
+    pub total_symbols: u64,
+}
+
+impl OutStreamResult {
+    /* Decompressed size over 'compressed_bytes', e.g. 'consumed' */
+    pub fn compression_ratio(&self, compressed_bytes: u64) -> f64 {
+        if compressed_bytes == 0 {
+            return 0.0;
+        }
+        self.written as f64 / compressed_bytes as f64
+    }
+}
+
+/* Counters behind the block statistics of OutStreamResult */
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct BlockStats {
+    pub(crate) dynamic_blocks: u32,
+    pub(crate) static_blocks: u32,
+    pub(crate) uncompressed_blocks: u32,
+    pub(crate) total_symbols: u64,
+}
+
+#[cfg(feature = "callbacks")]
+type BlockDoneFunc = dyn Fn(u64, u32, Option<usize>) + Send + Sync;
+#[cfg(feature = "callbacks")]
+type ErrorFunc = dyn Fn(&LibdeflateError) + Send + Sync;
+
+/*
+ * Observers set with LibdeflateDecompressor::set_on_block_done() and
+ * set_on_error().  They run on the decompressing thread in the middle of
+ * decoding, so they should return quickly, and they must not call back into
+ * the decompressor.
+ */
+#[cfg(feature = "callbacks")]
+#[derive(Default)]
+pub(crate) struct DecompressCallbacks {
+    pub(crate) on_block_done: Option<Box<BlockDoneFunc>>,
+    pub(crate) on_error: Option<Box<ErrorFunc>>,
+}
+
+/* Pass a failed result to the on_error callback, if any */
+#[cfg_attr(not(feature = "callbacks"), allow(unused_variables))]
+#[inline(always)]
+pub(crate) fn report_error<T>(
+    d: &LibdeflateDecompressor,
+    result: Result<T, LibdeflateError>,
+) -> Result<T, LibdeflateError> {
+    #[cfg(feature = "callbacks")]
+    if let (Err(err), Some(on_error)) = (&result, &d.callbacks.on_error) {
+        on_error(err);
+    }
+    result
+}
+
+/*
+ * Position of a block in the stream, passed to the block callback before the
+ * block is decoded.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeCursor {
+    /* BTYPE: 0 stored, 1 static Huffman, 2 dynamic Huffman */
+    pub block_type: u32,
+    pub is_final: bool,
+    /* Offset in bits of the block header in the input stream */
+    pub bit_position: u64,
+    /* Bytes decompressed before the block, for outputs reporting it */
+    pub uncompressed_offset: Option<u64>,
+}
+
+/*
+ * Codeword lengths of a dynamic Huffman block, as expanded from the precode
+ * and before the decode tables are built.
+ */
+#[cfg(any(test, feature = "debug-info"))]
+#[derive(Clone, Debug)]
+pub struct DynBlockLens {
+    pub litlen_lens: Vec<u8>,
+    pub offset_lens: Vec<u8>,
+    pub num_litlen_syms: usize,
+    pub num_offset_syms: usize,
+}
+
+/*
+ * Whether the precode codeword lengths 'lens' (in symbol order, 0 for unused
+ * symbols) form a complete prefix code: the sum of 2^-len over the used
+ * symbols must be exactly 1.  The decoder is more lenient, like libdeflate
+ * it also accepts an empty code or a single codeword of length 1.
+ */
+pub fn verify_precode(lens: &[u8; DEFLATE_NUM_PRECODE_SYMS]) -> bool {
+    let mut len_counts = [0u32; DEFLATE_MAX_PRE_CODEWORD_LEN + 1];
+    for &len in lens {
+        if len as usize > DEFLATE_MAX_PRE_CODEWORD_LEN {
+            return false;
+        }
+        len_counts[len as usize] += 1;
+    }
+
+    /* Codespace used out of 2^DEFLATE_MAX_PRE_CODEWORD_LEN, computed like
+     * build_decode_table() does */
+    let mut codespace_used = 0;
+    for count in &len_counts[1..] {
+        codespace_used = (codespace_used << 1) + count;
+    }
+    codespace_used == 1 << DEFLATE_MAX_PRE_CODEWORD_LEN
 }
 
 /*
@@ -77,8 +207,11 @@ pub type LenType = u8;
 #[macro_export]
 macro_rules! safety_check {
     ($cond:expr) => {
+        $crate::safety_check!($cond, BadData)
+    };
+    ($cond:expr, $error:ident) => {
         if !$cond {
-            return Err(LibdeflateError::BadData);
+            return Err(LibdeflateError::$error);
         }
     };
 }
@@ -99,6 +232,32 @@ pub(crate) struct _DecStruct {
     pub(crate) precode_decode_table: [u32; PRECODE_ENOUGH],
 }
 
+/*
+ * The refills read zeros past the end of the input, so a stream cut short
+ * surfaces as bad data once they fail to decode.  It is only a truncation if
+ * the failing symbol did use some of those zero bits, corrupt data at the end
+ * of the input is still BadData.
+ */
+#[cold]
+fn bad_data_error<I: DeflateInput, O: DeflateOutput>(
+    tmp_data: &DecompressTempData<I, O>,
+) -> LibdeflateError {
+    if tmp_data.overrun_count * 8 > tmp_data.bitsleft {
+        truncated_error(tmp_data)
+    } else {
+        LibdeflateError::BadData
+    }
+}
+
+#[cold]
+fn truncated_error<I: DeflateInput, O: DeflateOutput>(
+    tmp_data: &DecompressTempData<I, O>,
+) -> LibdeflateError {
+    LibdeflateError::Truncated {
+        compressed_bytes_processed: tmp_data.input_stream.tell_stream_pos() as u64,
+    }
+}
+
 /*
  * This is the actual DEFLATE decompression routine, lifted out of
  * deflate_decompress.c so that it can be compiled multiple times with different
@@ -109,6 +268,8 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
     d: &mut LibdeflateDecompressor,
     in_stream: &mut I,
     out_stream: &mut O,
+    mut on_block: impl FnMut(&DecodeCursor),
+    mut on_lens: impl FnMut(&[LenType], usize),
 ) -> Result<(), LibdeflateError> {
     let mut tmp_data = DecompressTempData {
         bitbuf: 0,
@@ -121,14 +282,44 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
         input_stream: in_stream,
         output_stream: out_stream,
     };
+    #[cfg(feature = "stats")]
+    {
+        d.stats = BlockStats::default();
+    }
+
+    /* Header position, type and output offset of the block being decoded */
+    #[cfg(feature = "callbacks")]
+    let mut current_block: Option<(u64, u32, Option<usize>)> = None;
 
     'block_done: loop {
+        check_input_error(tmp_data.input_stream)?;
+        check_output_error(tmp_data.output_stream)?;
+        if tmp_data.output_stream.is_quota_exceeded() {
+            return Err(LibdeflateError::InsufficientSpace);
+        }
+
+        #[cfg(feature = "callbacks")]
+        if let (Some((bit_position, block_type, start)), Some(on_block_done)) =
+            (current_block, &d.callbacks.on_block_done)
+        {
+            let end = tmp_data.output_stream.member_written();
+            on_block_done(
+                bit_position,
+                block_type,
+                start.zip(end).map(|(start, end)| end - start),
+            );
+        }
+
         if tmp_data.is_final_block {
             break;
         }
 
         /* Starting to read the next block.  */
 
+        /* The bits of overrun bytes were never read from the input */
+        let bit_position = (tmp_data.input_stream.tell_stream_pos() + tmp_data.overrun_count) * 8
+            - tmp_data.bitsleft;
+
         const_assert!(can_ensure(1 + 2 + 5 + 5 + 4));
         ensure_bits(&mut tmp_data, 1 + 2 + 5 + 5 + 4);
 
@@ -138,10 +329,33 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
         /* BTYPE: 2 bits  */
         tmp_data.block_type = pop_bits(&mut tmp_data, 2);
 
+        #[cfg(feature = "callbacks")]
+        {
+            current_block = Some((
+                bit_position as u64,
+                tmp_data.block_type,
+                tmp_data.output_stream.member_written(),
+            ));
+        }
+
+        on_block(&DecodeCursor {
+            block_type: tmp_data.block_type,
+            is_final: tmp_data.is_final_block,
+            bit_position: bit_position as u64,
+            uncompressed_offset: tmp_data
+                .output_stream
+                .member_written()
+                .map(|written| written as u64),
+        });
+
         let skip_decode_tables;
 
         if tmp_data.block_type == DEFLATE_BLOCKTYPE_DYNAMIC_HUFFMAN {
             /* Dynamic Huffman block.  */
+            #[cfg(feature = "stats")]
+            {
+                d.stats.dynamic_blocks += 1;
+            }
 
             /* The order in which precode lengths are stored.  */
             const DEFLATE_PRECODE_LENS_PERMUTATION: [u8; DEFLATE_NUM_PRECODE_SYMS] = [
@@ -174,7 +388,7 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
             }
 
             /* Build the decode table for the precode.  */
-            safety_check!(build_precode_decode_table(d));
+            safety_check!(build_precode_decode_table(d), InvalidHuffmanCode);
 
             /* Expand the literal/length and offset codeword lengths.  */
             let mut i = 0;
@@ -182,8 +396,7 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
                 ensure_bits(&mut tmp_data, DEFLATE_MAX_PRE_CODEWORD_LEN + 7);
 
                 /* (The code below assumes that the precode decode table
-                 * does not have any subtables.)  */
-                const_assert!(PRECODE_TABLEBITS == DEFLATE_MAX_PRE_CODEWORD_LEN);
+                 * does not have any subtables, see PRECODE_TABLEBITS.)  */
 
                 /* Read the next precode symbol.  */
                 let entry = d.l.precode_decode_table
@@ -192,7 +405,8 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
                 let presym = entry >> HUFFDEC_RESULT_SHIFT;
 
                 if presym < 16 {
-                    /* Explicit codeword length  */
+                    /* Explicit codeword length; build_decode_table() rejects
+                     * it if it exceeds the limit of the code it belongs to  */
                     d.l.lens[i] = presym as LenType;
                     i += 1;
                     continue;
@@ -220,7 +434,7 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
 
                 if presym == 16 {
                     /* Repeat the previous length 3 - 6 times  */
-                    safety_check!(i != 0);
+                    safety_check!(i != 0, InvalidHuffmanCode);
                     let rep_val = d.l.lens[i - 1];
                     const_assert!(3 + ((1 << 2) - 1) == 6);
                     let rep_count = (3 + pop_bits(&mut tmp_data, 2)) as usize;
@@ -254,27 +468,62 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
                     i += rep_count;
                 }
             }
+            on_lens(
+                &d.l.lens[..tmp_data.num_litlen_syms + tmp_data.num_offset_syms],
+                tmp_data.num_litlen_syms,
+            );
             skip_decode_tables = false;
         } else if tmp_data.block_type == DEFLATE_BLOCKTYPE_UNCOMPRESSED {
             /* Uncompressed block: copy 'len' bytes literally from the input
              * buffer to the output buffer.  */
+            #[cfg(feature = "stats")]
+            {
+                d.stats.uncompressed_blocks += 1;
+            }
 
             align_input(&mut tmp_data)?;
 
-            safety_check!(tmp_data.input_stream.ensure_length(4));
+            let mut len_bytes = [0; 4];
+            if !read_bytes(&mut tmp_data, &mut len_bytes) {
+                return Err(truncated_error(&tmp_data));
+            }
 
-            let len = unsafe { read_u16(&mut tmp_data) };
-            let nlen = unsafe { read_u16(&mut tmp_data) };
+            let len = u16::from_le_bytes([len_bytes[0], len_bytes[1]]);
+            let nlen = u16::from_le_bytes([len_bytes[2], len_bytes[3]]);
 
-            safety_check!(len == !nlen);
+            if len != !nlen {
+                return Err(LibdeflateError::BadStoredBlock {
+                    len,
+                    nlen,
+                    bit_position: bit_position as u64,
+                });
+            }
 
-            safety_check!(tmp_data
+            if !tmp_data
                 .input_stream
-                .read_exact_into(tmp_data.output_stream, len as usize));
+                .read_exact_into(tmp_data.output_stream, len as usize)
+            {
+                /* The copy stops either when the input ends or when the
+                 * output has no more room, as with the Huffman path report
+                 * the latter as InsufficientSpace */
+                check_output_error(tmp_data.output_stream)?;
+                if tmp_data.output_stream.is_quota_exceeded()
+                    || tmp_data.output_stream.get_available_buffer().is_empty()
+                {
+                    return Err(LibdeflateError::InsufficientSpace);
+                }
+                return Err(truncated_error(&tmp_data));
+            }
 
             continue 'block_done;
         } else {
-            safety_check!(tmp_data.block_type == DEFLATE_BLOCKTYPE_STATIC_HUFFMAN);
+            if tmp_data.block_type != DEFLATE_BLOCKTYPE_STATIC_HUFFMAN {
+                return Err(bad_data_error(&tmp_data));
+            }
+            #[cfg(feature = "stats")]
+            {
+                d.stats.static_blocks += 1;
+            }
 
             /*
              * Static Huffman block: build the decode tables for the static
@@ -318,20 +567,20 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
 
         /* Decompressing a Huffman block (either dynamic or static)  */
         if !skip_decode_tables {
-            safety_check!(build_offset_decode_table(
-                d,
-                tmp_data.num_litlen_syms,
-                tmp_data.num_offset_syms,
-            ));
-            safety_check!(build_litlen_decode_table(
-                d,
-                tmp_data.num_litlen_syms,
-                tmp_data.num_offset_syms,
-            ));
+            safety_check!(
+                build_offset_decode_table(d, tmp_data.num_litlen_syms, tmp_data.num_offset_syms),
+                InvalidHuffmanCode
+            );
+            safety_check!(
+                build_litlen_decode_table(d, tmp_data.num_litlen_syms, tmp_data.num_offset_syms),
+                InvalidHuffmanCode
+            );
         }
 
-        /* The main DEFLATE decode loop  */
-        while tmp_data.overrun_count < (size_of::<usize>() / 8) {
+        /* The main DEFLATE decode loop.  The refills may look past the end
+         * of the input, stop only once the zero bits they added are being
+         * decoded, as align_input() would reject them anyway.  */
+        while tmp_data.overrun_count == 0 || tmp_data.overrun_count <= (tmp_data.bitsleft >> 3) {
             /* Decode a litlen symbol.  */
             ensure_bits(&mut tmp_data, DEFLATE_MAX_LITLEN_CODEWORD_LEN);
             let mut entry = d.litlen_decode_table[bits(&mut tmp_data, LITLEN_TABLEBITS) as usize];
@@ -345,10 +594,15 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
             remove_bits(&mut tmp_data, (entry & HUFFDEC_LENGTH_MASK) as usize);
             if (entry & HUFFDEC_LITERAL) != 0 {
                 /* Literal  */
+                #[cfg(feature = "stats")]
+                {
+                    d.stats.total_symbols += 1;
+                }
                 if !tmp_data
                     .output_stream
                     .write(&((entry >> HUFFDEC_RESULT_SHIFT) as u8).to_ne_bytes())
                 {
+                    check_output_error(tmp_data.output_stream)?;
                     return Err(LibdeflateError::InsufficientSpace);
                 }
                 continue;
@@ -376,8 +630,16 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
             if unlikely(length == HUFFDEC_END_OF_BLOCK_LENGTH) {
                 continue 'block_done;
             }
+            #[cfg(feature = "stats")]
+            {
+                d.stats.total_symbols += 1;
+            }
 
-            /* Decode the match offset.  */
+            /* Decode the match offset.  DEFLATE64 lengths may have used up to
+             * 16 extra bits, which only matters for a narrow bitbuffer.  */
+            if !can_ensure(DEFLATE64_MAX_EXTRA_LENGTH_BITS + DEFLATE_MAX_OFFSET_CODEWORD_LEN) {
+                ensure_bits(&mut tmp_data, DEFLATE_MAX_OFFSET_CODEWORD_LEN);
+            }
 
             entry = d.offset_decode_table[bits(&mut tmp_data, OFFSET_TABLEBITS) as usize];
             if (entry & HUFFDEC_SUBTABLE_POINTER) != 0 {
@@ -425,9 +687,16 @@ pub(crate) fn deflate_decompress_template<I: DeflateInput, O: DeflateOutput>(
             /* The match source must not begin before the beginning of the
              * output buffer.  */
 
-            safety_check!(tmp_data
+            if !tmp_data
                 .output_stream
-                .copy_forward(offset as usize, length as usize));
+                .copy_forward(offset as usize, length as usize)
+            {
+                check_output_error(tmp_data.output_stream)?;
+                if tmp_data.output_stream.is_quota_exceeded() {
+                    return Err(LibdeflateError::InsufficientSpace);
+                }
+                return Err(bad_data_error(&tmp_data));
+            }
         }
     }
 
@@ -27,9 +27,28 @@
  * OTHER DEALINGS IN THE SOFTWARE.
  */
 
-use crate::decompress_utils::libdeflate_deflate_decompress;
+use crate::decompress_deflate::{report_error, DecodeCursor};
+use crate::decompress_utils::{
+    check_input_error, deflate_decompress_stream, flush_output, truncation_error,
+};
 use crate::gzip_constants::*;
-use crate::{safety_check, DeflateInput, DeflateOutput, LibdeflateDecompressor, LibdeflateError};
+use crate::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
+use crate::streams::deflate_write_output::DeflateWriteOutput;
+use crate::{
+    libdeflate_alloc_decompressor, safety_check, DeflateInput, DeflateOutput,
+    LibdeflateDecompressor, LibdeflateError, OutStreamResult,
+};
+use crc32fast::Hasher;
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+#[cfg(not(target_family = "wasm"))]
+use std::time::Instant;
+
+/* DEFLATE can't expand a stream by more than this factor */
+pub(crate) const DEFLATE_MAX_EXPANSION: usize = 1032;
+
+const GUNZIP_BUF_SIZE: usize = 1024 * 64;
 
 // struct flush_buffer_data {
 // 	flush_buffer_func *user_func;
@@ -43,73 +62,539 @@ use crate::{safety_check, DeflateInput, DeflateOutput, LibdeflateDecompressor, L
 // 	return fdata->user_func(fdata->user_data, buffer, len);
 // }
 
-pub fn libdeflate_gzip_decompress<I: DeflateInput, O: DeflateOutput>(
-    d: &mut LibdeflateDecompressor,
+/* Read header bytes, accumulating them into the header CRC for FHCRC */
+#[inline(always)]
+fn read_header_bytes<I: DeflateInput>(
     in_stream: &mut I,
-    out_stream: &mut O,
+    header_crc: &mut Hasher,
+    out_data: &mut [u8],
+) -> bool {
+    if !in_stream.read_exact(out_data) {
+        return false;
+    }
+    header_crc.update(out_data);
+    true
+}
+
+/* Longest file name or comment kept in a GzipHeader */
+pub const GZIP_MAX_STRING_LEN: usize = 65535;
+
+/* The optional fields of a gzip member header */
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GzipHeader {
+    pub mtime: u32,
+    pub xfl: u8,
+    pub os: u8,
+    pub extra: Option<Vec<u8>>,
+    /* FNAME and FCOMMENT, converted lossily from UTF-8 */
+    pub name: Option<String>,
+    pub comment: Option<String>,
+    /* FTEXT, the data is probably text.  Informational only, it doesn't
+     * affect decompression. */
+    pub is_text: bool,
+}
+
+/*
+ * Read a zero terminated header string, keeping it if 'string' is provided.
+ * Kept strings longer than GZIP_MAX_STRING_LEN are rejected.
+ */
+fn read_header_string<I: DeflateInput>(
+    in_stream: &mut I,
+    header_crc: &mut Hasher,
+    mut string: Option<&mut Vec<u8>>,
+) -> bool {
+    let mut byte = [0];
+    loop {
+        if !read_header_bytes(in_stream, header_crc, &mut byte) {
+            return false;
+        }
+        if byte[0] == 0 {
+            return true;
+        }
+        if let Some(string) = string.as_mut() {
+            if string.len() == GZIP_MAX_STRING_LEN {
+                return false;
+            }
+            string.push(byte[0]);
+        }
+    }
+}
+
+/*
+ * Parse a gzip member header, leaving the input at the start of the compressed
+ * data.  If 'header' is provided, it is filled with the header fields.
+ */
+pub(crate) fn read_gzip_header<I: DeflateInput>(
+    in_stream: &mut I,
+    mut header_fields: Option<&mut GzipHeader>,
+    allow_reserved_flags: bool,
 ) -> Result<(), LibdeflateError> {
-    /* ID1 */
-    if in_stream.read_byte() != GZIP_ID1 {
+    let mut header_crc = Hasher::new();
+
+    /* ID1, ID2, CM, FLG, MTIME, XFL, OS */
+    let mut header = [0; GZIP_MIN_HEADER_SIZE];
+    if !read_header_bytes(in_stream, &mut header_crc, &mut header) {
+        check_input_error(in_stream)?;
         return Err(LibdeflateError::BadData);
     }
+
+    /* ID1 */
+    safety_check!(header[0] == GZIP_ID1, BadMagic);
     /* ID2 */
-    if in_stream.read_byte() != GZIP_ID2 {
-        return Err(LibdeflateError::BadData);
-    }
+    safety_check!(header[1] == GZIP_ID2, BadMagic);
     /* CM */
-    if in_stream.read_byte() != GZIP_CM_DEFLATE {
-        return Err(LibdeflateError::BadData);
-    }
-    let flg = in_stream.read_byte();
+    safety_check!(header[2] == GZIP_CM_DEFLATE, UnsupportedMethod);
 
-    /* MTIME */
-    safety_check!(in_stream.move_stream_pos(4));
-    /* XFL */
-    safety_check!(in_stream.move_stream_pos(1));
-    /* OS */
-    safety_check!(in_stream.move_stream_pos(1));
+    let flg = header[3];
 
-    if (flg & GZIP_FRESERVED) != 0 {
-        return Err(LibdeflateError::BadData);
+    safety_check!(
+        allow_reserved_flags || (flg & GZIP_FRESERVED) == 0,
+        ReservedFlags
+    );
+
+    if let Some(fields) = header_fields.as_mut() {
+        **fields = GzipHeader {
+            mtime: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+            xfl: header[8],
+            os: header[9],
+            is_text: (flg & GZIP_FTEXT) != 0,
+            ..GzipHeader::default()
+        };
     }
 
     /* Extra field */
     if (flg & GZIP_FEXTRA) != 0 {
-        let xlen = in_stream.read_le_u16();
-        safety_check!(in_stream.move_stream_pos(xlen as isize));
+        let mut xlen = [0; 2];
+        safety_check!(read_header_bytes(in_stream, &mut header_crc, &mut xlen));
+
+        let mut extra = header_fields
+            .as_mut()
+            .map(|fields| fields.extra.insert(Vec::new()));
+        let mut remaining = u16::from_le_bytes(xlen) as usize;
+        let mut extra_chunk = [0; 64];
+        while remaining > 0 {
+            let amount = min(remaining, extra_chunk.len());
+            safety_check!(read_header_bytes(
+                in_stream,
+                &mut header_crc,
+                &mut extra_chunk[..amount]
+            ));
+            if let Some(extra) = extra.as_mut() {
+                extra.extend_from_slice(&extra_chunk[..amount]);
+            }
+            remaining -= amount;
+        }
     }
 
     /* Original file name (zero terminated) */
     if (flg & GZIP_FNAME) != 0 {
-        while in_stream.read_byte() != 0 {}
+        let mut name = header_fields.as_ref().map(|_| Vec::new());
+        safety_check!(read_header_string(
+            in_stream,
+            &mut header_crc,
+            name.as_mut()
+        ));
+        if let Some(fields) = header_fields.as_mut() {
+            fields.name = name.map(|name| String::from_utf8_lossy(&name).into_owned());
+        }
     }
 
     /* File comment (zero terminated) */
     if (flg & GZIP_FCOMMENT) != 0 {
-        while in_stream.read_byte() != 0 {}
+        let mut comment = header_fields.as_ref().map(|_| Vec::new());
+        safety_check!(read_header_string(
+            in_stream,
+            &mut header_crc,
+            comment.as_mut()
+        ));
+        if let Some(fields) = header_fields.as_mut() {
+            fields.comment = comment.map(|comment| String::from_utf8_lossy(&comment).into_owned());
+        }
     }
 
     /* CRC16 for gzip header */
     if (flg & GZIP_FHCRC) != 0 {
-        safety_check!(in_stream.move_stream_pos(2));
+        let mut hcrc = [0; 2];
+        safety_check!(in_stream.read_exact(&mut hcrc));
+        safety_check!(
+            header_crc.finalize() as u16 == u16::from_le_bytes(hcrc),
+            HeaderCrcMismatch
+        );
     }
 
-    /* Compressed data  */
-    libdeflate_deflate_decompress(d, in_stream, out_stream)?;
+    Ok(())
+}
 
-    let result = out_stream
-        .final_flush()
-        .map_err(|_| LibdeflateError::InsufficientSpace)?;
+/* Whether the input is at the start of a gzip member, without consuming it */
+pub(crate) fn at_gzip_member<I: DeflateInput>(in_stream: &mut I) -> bool {
+    let mut magic = [0; 2];
+    let read = in_stream.read(&mut magic);
+    in_stream.move_stream_pos(-(read as isize));
+    read == magic.len() && magic == [GZIP_ID1, GZIP_ID2]
+}
+
+/*
+ * Skip bytes until the input is at something that looks like the start of a
+ * gzip member (ID1, ID2 and CM = DEFLATE) or at the end of the stream.
+ * Returns the number of bytes skipped.
+ */
+pub(crate) fn skip_to_gzip_member<I: DeflateInput>(in_stream: &mut I) -> usize {
+    let mut skipped = 0;
+    let mut magic = [0; 3];
+    while in_stream.ensure_length(1) {
+        let read = in_stream.read(&mut magic);
+        in_stream.move_stream_pos(-(read as isize));
+        if read == magic.len() && magic == [GZIP_ID1, GZIP_ID2, GZIP_CM_DEFLATE] {
+            break;
+        }
+        in_stream.move_stream_pos(1);
+        skipped += 1;
+    }
+    skipped
+}
+
+/*
+ * Flush the output of a member whose compressed data has been fully decoded
+ * and check it against the CRC32 and ISIZE fields of the gzip trailer.
+ */
+pub(crate) fn finish_gzip_member<I: DeflateInput, O: DeflateOutput>(
+    in_stream: &mut I,
+    out_stream: &mut O,
+) -> Result<OutStreamResult, LibdeflateError> {
+    let result = flush_output(out_stream)?;
+
+    /* CRC32 and ISIZE */
+    let mut trailer = [0; GZIP_FOOTER_SIZE];
+    if !in_stream.read_exact(&mut trailer) {
+        check_input_error(in_stream)?;
+        return Err(LibdeflateError::Truncated {
+            compressed_bytes_processed: in_stream.tell_stream_pos() as u64,
+        });
+    }
+    let gzip_crc = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+    let gzip_size = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
 
-    let gzip_crc = in_stream.read_le_u32();
     if result.crc32 != gzip_crc {
-        return Err(LibdeflateError::BadData);
+        return Err(LibdeflateError::ChecksumMismatch {
+            expected: gzip_crc,
+            actual: result.crc32,
+        });
     }
 
-    /* ISIZE */
-    if result.written as u32 != in_stream.read_le_u32() {
-        return Err(LibdeflateError::BadData);
+    if result.written as u32 != gzip_size {
+        return Err(LibdeflateError::LengthMismatch {
+            expected: gzip_size,
+            actual: result.written as u32,
+        });
     }
 
-    Ok(())
+    Ok(result)
+}
+
+/* A decompressed gzip member, returned by libdeflate_gzip_decompress() */
+#[derive(Clone, Debug)]
+pub struct GzipResult {
+    pub header: GzipHeader,
+    /* Size of the member, including its header and trailer */
+    pub compressed_bytes: u64,
+    pub decompressed_bytes: u64,
+    pub duration: Duration,
+}
+
+/*
+ * Decompress the gzip member at the start of 'in_stream'.  The header is
+ * kept, so members with a FNAME or FCOMMENT longer than GZIP_MAX_STRING_LEN
+ * are rejected.  There is no clock on wasm, where the duration is left at
+ * zero.
+ */
+pub fn libdeflate_gzip_decompress<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+) -> Result<GzipResult, LibdeflateError> {
+    #[cfg(not(target_family = "wasm"))]
+    let start = Instant::now();
+    let mut header = GzipHeader::default();
+    let result = libdeflate_gzip_decompress_with_header(d, in_stream, out_stream, &mut header)?;
+
+    /* Instant::now() panics on wasm32-unknown-unknown */
+    #[cfg(not(target_family = "wasm"))]
+    let duration = start.elapsed();
+    #[cfg(target_family = "wasm")]
+    let duration = Duration::ZERO;
+
+    Ok(GzipResult {
+        header,
+        compressed_bytes: result.consumed as u64,
+        decompressed_bytes: result.written as u64,
+        duration,
+    })
+}
+
+/*
+ * libdeflate_gzip_decompress() for callers that only need the data: the
+ * header fields are skipped and nothing is timed.
+ */
+pub(crate) fn gzip_decompress<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+) -> Result<OutStreamResult, LibdeflateError> {
+    gzip_decompress_member(d, in_stream, out_stream, None, |_| true, |_| {})
+}
+
+/* Like libdeflate_gzip_decompress(), also returning the member header */
+pub fn libdeflate_gzip_decompress_with_header<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+    header: &mut GzipHeader,
+) -> Result<OutStreamResult, LibdeflateError> {
+    gzip_decompress_member(d, in_stream, out_stream, Some(header), |_| true, |_| {})
+}
+
+/*
+ * Like libdeflate_gzip_decompress_with_header(), calling 'on_header' once the
+ * header has been parsed, before any data is decompressed.  If it returns
+ * false the member is skipped with HeaderRejected: nothing is written to
+ * 'out_stream' and 'in_stream' is left at the start of the compressed data.
+ */
+pub fn libdeflate_gzip_decompress_on_header<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+    header: &mut GzipHeader,
+    on_header: impl FnOnce(&GzipHeader) -> bool,
+) -> Result<OutStreamResult, LibdeflateError> {
+    gzip_decompress_member(d, in_stream, out_stream, Some(header), on_header, |_| {})
+}
+
+/*
+ * Like libdeflate_gzip_decompress(), calling 'on_block' before each DEFLATE
+ * block of the member with its position, e.g. to build an index.
+ */
+pub fn libdeflate_gzip_decompress_on_block<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+    on_block: impl FnMut(&DecodeCursor),
+) -> Result<OutStreamResult, LibdeflateError> {
+    gzip_decompress_member(d, in_stream, out_stream, None, |_| true, on_block)
+}
+
+fn gzip_decompress_member<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+    header: Option<&mut GzipHeader>,
+    on_header: impl FnOnce(&GzipHeader) -> bool,
+    on_block: impl FnMut(&DecodeCursor),
+) -> Result<OutStreamResult, LibdeflateError> {
+    let result = decode_gzip_member(d, in_stream, out_stream, header, on_header, on_block);
+    report_error(d, result)
+}
+
+fn decode_gzip_member<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+    header: Option<&mut GzipHeader>,
+    on_header: impl FnOnce(&GzipHeader) -> bool,
+    on_block: impl FnMut(&DecodeCursor),
+) -> Result<OutStreamResult, LibdeflateError> {
+    let start_pos = in_stream.tell_stream_pos();
+
+    match header {
+        Some(header) => {
+            read_gzip_header(in_stream, Some(&mut *header), d.allow_reserved_flags)
+                .map_err(|err| truncation_error(in_stream, err))?;
+            if !on_header(header) {
+                return Err(LibdeflateError::HeaderRejected);
+            }
+        }
+        None => read_gzip_header(in_stream, None, d.allow_reserved_flags)
+            .map_err(|err| truncation_error(in_stream, err))?,
+    }
+
+    /* Compressed data  */
+    deflate_decompress_stream(d, in_stream, out_stream, on_block)?;
+
+    let mut result = finish_gzip_member(in_stream, out_stream)?;
+    result.consumed = in_stream.tell_stream_pos() - start_pos;
+    #[cfg(feature = "stats")]
+    {
+        result.dynamic_blocks = d.stats.dynamic_blocks;
+        result.static_blocks = d.stats.static_blocks;
+        result.uncompressed_blocks = d.stats.uncompressed_blocks;
+        result.total_symbols = d.stats.total_symbols;
+    }
+    Ok(result)
+}
+
+/*
+ * Clamp the ISIZE of the last member of a 'compressed_size' bytes gzip
+ * stream to a plausible decompressed size.  ISIZE is the size modulo 2^32
+ * and only covers the last member, so this is only a capacity hint.
+ */
+fn isize_hint(isize: u32, compressed_size: usize) -> Option<usize> {
+    if compressed_size < GZIP_MIN_OVERHEAD {
+        return None;
+    }
+    Some(min(
+        isize as usize,
+        compressed_size.saturating_mul(DEFLATE_MAX_EXPANSION),
+    ))
+}
+
+/* Decompressed size hint read from the trailer of an in-memory gzip stream */
+pub fn gzip_isize_hint(data: &[u8]) -> Option<usize> {
+    let trailer = data.len().checked_sub(4)?;
+    let isize = u32::from_le_bytes(data[trailer..].try_into().unwrap());
+    isize_hint(isize, data.len())
+}
+
+/*
+ * Decompressed size hint read from the trailer of a seekable gzip stream.
+ * The stream position is restored; None is returned if seeking fails.
+ */
+pub fn gzip_isize_hint_seek<R: Read + Seek>(reader: &mut R) -> Option<usize> {
+    let start = reader.stream_position().ok()?;
+    let end = reader.seek(SeekFrom::End(0)).ok()?;
+
+    let mut isize = [0; 4];
+    let read_ok = end >= start + 4
+        && reader.seek(SeekFrom::End(-4)).is_ok()
+        && reader.read_exact(&mut isize).is_ok();
+    reader.seek(SeekFrom::Start(start)).ok()?;
+
+    if !read_ok {
+        return None;
+    }
+    isize_hint(u32::from_le_bytes(isize), (end - start) as usize)
+}
+
+/*
+ * Decompress all the gzip members in 'data' into a Vec, preallocated from
+ * the ISIZE of the trailer.
+ */
+pub fn gunzip_to_vec(data: &[u8]) -> Result<Vec<u8>, LibdeflateError> {
+    let mut output = Vec::new();
+    gunzip_to(data, &mut output)?;
+    Ok(output)
+}
+
+/*
+ * Decompress all the gzip members in 'data', appending the output to 'out'.
+ * Matches are resolved in the output stream's own window, so they can't
+ * reach back into the data that was already in 'out'.  The result covers
+ * all the members; on error 'out' is restored to its previous length.
+ */
+pub fn gunzip_to(data: &[u8], out: &mut Vec<u8>) -> Result<OutStreamResult, LibdeflateError> {
+    let start_len = out.len();
+    out.reserve(gzip_isize_hint(data).unwrap_or(0));
+
+    let result = gunzip_members(data, out);
+    if result.is_err() {
+        out.truncate(start_len);
+    }
+    result
+}
+
+fn gunzip_members(mut data: &[u8], out: &mut Vec<u8>) -> Result<OutStreamResult, LibdeflateError> {
+    let mut input_stream = DeflateChunkedBufferInput::new(|buf| data.read(buf), GUNZIP_BUF_SIZE);
+    let mut output_stream = DeflateWriteOutput::new(out, GUNZIP_BUF_SIZE);
+    let mut decompressor = libdeflate_alloc_decompressor();
+
+    let mut total = OutStreamResult::default();
+    let mut crc32 = Hasher::new();
+
+    while input_stream.ensure_length(1) {
+        let result = gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?;
+        crc32.combine(&Hasher::new_with_initial_len(
+            result.crc32,
+            result.written as u64,
+        ));
+        total.written += result.written;
+        total.consumed += result.consumed;
+        total.dynamic_blocks += result.dynamic_blocks;
+        total.static_blocks += result.static_blocks;
+        total.uncompressed_blocks += result.uncompressed_blocks;
+        total.total_symbols += result.total_symbols;
+    }
+    total.crc32 = crc32.finalize();
+    Ok(total)
+}
+
+/*
+ * std::io::Read adapter decompressing the gzip members in a byte slice.  The
+ * decompressor can't stop in the middle of a member, so each read() that
+ * finds no data left decompresses a whole member into an internal buffer:
+ * memory use grows with the size of the largest member.
+ */
+pub struct GzipSliceDecoder<'a> {
+    input_stream: DeflateChunkedBufferInput<'a>,
+    output_stream: DeflateWriteOutput<'static, Vec<u8>>,
+    decompressor: Box<LibdeflateDecompressor>,
+    /* Bytes of the current member already returned by read() */
+    returned: usize,
+    failed: bool,
+}
+
+impl<'a> GzipSliceDecoder<'a> {
+    pub fn new(mut compressed: &'a [u8]) -> Self {
+        Self {
+            input_stream: DeflateChunkedBufferInput::new(
+                move |buf| compressed.read(buf),
+                GUNZIP_BUF_SIZE,
+            ),
+            output_stream: DeflateWriteOutput::new(Vec::new(), GUNZIP_BUF_SIZE),
+            decompressor: Box::new(libdeflate_alloc_decompressor()),
+            returned: 0,
+            failed: false,
+        }
+    }
+}
+
+impl Read for GzipSliceDecoder<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let output = self.output_stream.get_ref();
+                let pending = &output[self.returned..];
+                if !pending.is_empty() || buf.is_empty() {
+                    let amount = min(pending.len(), buf.len());
+                    buf[..amount].copy_from_slice(&pending[..amount]);
+                    self.returned += amount;
+                    return Ok(amount);
+                }
+            }
+
+            /* The input is left in the middle of the failed member */
+            if self.failed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "gzip decompression already failed",
+                ));
+            }
+            if !self.input_stream.ensure_length(1) {
+                return Ok(0);
+            }
+
+            self.output_stream.get_mut().clear();
+            self.returned = 0;
+            if let Err(err) = gzip_decompress(
+                &mut self.decompressor,
+                &mut self.input_stream,
+                &mut self.output_stream,
+            ) {
+                self.failed = true;
+                self.output_stream.get_mut().clear();
+                return Err(match err {
+                    LibdeflateError::ReadError(err) | LibdeflateError::WriteError(err) => err,
+                    err => io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)),
+                });
+            }
+        }
+    }
 }
@@ -27,16 +27,28 @@
  * 64-bit platforms have a significant advantage: they get a bigger bitbuffer
  * which they have to fill less often.
  */
+#[cfg(any(test, feature = "debug-info"))]
+use crate::decompress_deflate::DynBlockLens;
 use crate::decompress_deflate::{
-    deflate_decompress_template, LenType, LITLEN_ENOUGH, LITLEN_TABLEBITS, OFFSET_ENOUGH,
-    OFFSET_TABLEBITS, PRECODE_ENOUGH, PRECODE_TABLEBITS,
+    deflate_decompress_template, report_error, DecodeCursor, LenType, LITLEN_ENOUGH,
+    LITLEN_TABLEBITS, OFFSET_ENOUGH, OFFSET_TABLEBITS, PRECODE_ENOUGH, PRECODE_TABLEBITS,
 };
 use crate::deflate_constants::*;
-use crate::{safety_check, DeflateInput, DeflateOutput, LibdeflateDecompressor, LibdeflateError};
+use crate::{
+    DeflateInput, DeflateOutput, LibdeflateDecompressor, LibdeflateError, OutStreamResult,
+};
 use nightly_quirks::branch_pred::{likely, unlikely};
 
+#[cfg(not(target_pointer_width = "32"))]
 type BitBufType = usize;
 
+/*
+ * 32-bit targets would only get 31 bits and refill about twice as often, use
+ * a 64-bit bitbuffer filled with two word loads instead.
+ */
+#[cfg(target_pointer_width = "32")]
+type BitBufType = u64;
+
 pub struct DecompressTempData<'a, I: DeflateInput, O: DeflateOutput> {
     pub bitbuf: BitBufType,
     pub bitsleft: usize,
@@ -144,6 +156,25 @@ pub fn fill_bits_bytewise<I: DeflateInput, O: DeflateOutput>(data: &mut Decompre
  * -, >>, and &.  Also the - and & must be completed before 'bitsleft' can be
  * updated, while the current solution updates 'bitsleft' with no dependencies.
  */
+#[cfg(not(target_pointer_width = "32"))]
+#[inline(always)]
+unsafe fn load_le_bitbuf<I: DeflateInput>(input_stream: &mut I) -> BitBufType {
+    input_stream.get_le_word_no_advance()
+}
+
+/* The caller ensured that the 8 bytes are available, so moving over the
+ * first word can't refill the input */
+#[cfg(target_pointer_width = "32")]
+#[inline(always)]
+unsafe fn load_le_bitbuf<I: DeflateInput>(input_stream: &mut I) -> BitBufType {
+    const WORD_BYTES: isize = std::mem::size_of::<usize>() as isize;
+    let low = input_stream.get_le_word_no_advance() as u64;
+    input_stream.move_stream_pos(WORD_BYTES);
+    let high = input_stream.get_le_word_no_advance() as u64;
+    input_stream.move_stream_pos(-WORD_BYTES);
+    low | (high << 32)
+}
+
 #[inline(always)]
 pub unsafe fn fill_bits_wordwise<I: DeflateInput, O: DeflateOutput>(
     data: &mut DecompressTempData<I, O>,
@@ -151,7 +182,7 @@ pub unsafe fn fill_bits_wordwise<I: DeflateInput, O: DeflateOutput>(
     /* BITBUF_NBITS must be all 1's in binary, see above */
     // const_assert!((BITBUF_NBITS & (BITBUF_NBITS + 1)) == 0);
 
-    data.bitbuf |= data.input_stream.get_le_word_no_advance() << data.bitsleft;
+    data.bitbuf |= load_le_bitbuf(data.input_stream) << data.bitsleft;
     data.input_stream
         .move_stream_pos(((data.bitsleft ^ BITBUF_NBITS) >> 3) as isize);
     data.bitsleft |= BITBUF_NBITS & !7;
@@ -243,7 +274,12 @@ pub fn pop_bits<I: DeflateInput, O: DeflateOutput>(
 pub fn align_input<I: DeflateInput, O: DeflateOutput>(
     data: &mut DecompressTempData<I, O>,
 ) -> Result<(), LibdeflateError> {
-    safety_check!(data.overrun_count <= (data.bitsleft >> 3));
+    /* Some of the zero bytes added past the end of the input were used */
+    if data.overrun_count > (data.bitsleft >> 3) {
+        return Err(LibdeflateError::Truncated {
+            compressed_bytes_processed: data.input_stream.tell_stream_pos() as u64,
+        });
+    }
     data.input_stream
         .move_stream_pos(-(((data.bitsleft >> 3) - data.overrun_count) as isize));
     data.overrun_count = 0;
@@ -253,16 +289,79 @@ pub fn align_input<I: DeflateInput, O: DeflateOutput>(
 }
 
 /*
- * Read a 16-bit value from the input.  This must have been preceded by a call
- * to ALIGN_INPUT(), and the caller must have already checked for overrun.
+ * Read 'dst.len()' bytes from the input.  The bitbuffer must be byte aligned;
+ * any whole bytes still held in it are drained first (excluding the zero bytes
+ * added by an overread), then the remainder is read from the input stream.
+ * Returns false if the input ended before 'dst' could be filled.
  */
 #[inline(always)]
-pub unsafe fn read_u16<I: DeflateInput, O: DeflateOutput>(
+pub fn read_bytes<I: DeflateInput, O: DeflateOutput>(
     data: &mut DecompressTempData<I, O>,
-) -> u16 {
-    let mut bytes = [0, 0];
-    data.input_stream.read_unchecked(&mut bytes);
-    u16::from_le_bytes(bytes)
+    dst: &mut [u8],
+) -> bool {
+    debug_assert!(data.bitsleft & 7 == 0);
+
+    let mut drained = 0;
+    while drained < dst.len() && (data.bitsleft >> 3) > data.overrun_count {
+        dst[drained] = pop_bits(data, 8) as u8;
+        drained += 1;
+    }
+
+    data.input_stream.read_exact(&mut dst[drained..])
+}
+
+/*
+ * The gzip header is read byte by byte, a header cut short surfaces as
+ * BadData.  Report it as Truncated if the input is indeed exhausted.  The
+ * DEFLATE data is handled by the decoder itself, see
+ * deflate_decompress_template().
+ */
+pub(crate) fn truncation_error<I: DeflateInput>(
+    in_stream: &mut I,
+    err: LibdeflateError,
+) -> LibdeflateError {
+    match err {
+        LibdeflateError::BadData if !in_stream.ensure_length(1) => LibdeflateError::Truncated {
+            compressed_bytes_processed: in_stream.tell_stream_pos() as u64,
+        },
+        err => err,
+    }
+}
+
+/*
+ * Report the error that stopped the input stream, if there has been one.  The
+ * decoder treats a failed read as the end of the input, so this is checked at
+ * block boundaries to tell a read error apart from truncated data.
+ */
+#[inline(always)]
+pub fn check_input_error<I: DeflateInput>(in_stream: &mut I) -> Result<(), LibdeflateError> {
+    match in_stream.take_read_error() {
+        Some(err) => Err(LibdeflateError::ReadError(err)),
+        None => Ok(()),
+    }
+}
+
+/*
+ * Report the error that stopped the output stream, if there has been one.  The
+ * decoder sees a failed write as a full output, so this is checked wherever
+ * that would be reported as InsufficientSpace.
+ */
+#[inline(always)]
+pub fn check_output_error<O: DeflateOutput>(out_stream: &mut O) -> Result<(), LibdeflateError> {
+    match out_stream.take_write_error() {
+        Some(err) => Err(LibdeflateError::WriteError(err)),
+        None => Ok(()),
+    }
+}
+
+/* Flush the end of the member, a failed write is reported as WriteError */
+pub fn flush_output<O: DeflateOutput>(
+    out_stream: &mut O,
+) -> Result<OutStreamResult, LibdeflateError> {
+    out_stream.final_flush().or_else(|()| {
+        check_output_error(out_stream)?;
+        Err(LibdeflateError::InsufficientSpace)
+    })
 }
 
 /*****************************************************************************
@@ -322,6 +421,18 @@ pub const HUFFDEC_SUBTABLE_POINTER: u32 = 0x80000000;
  */
 pub const HUFFDEC_LITERAL: u32 = 0x40000000;
 
+/*
+ * Number of subtable entries referenced by the subtable pointers of the main
+ * decode table of 'table_bits' bits.
+ */
+pub fn subtable_entries_used(decode_table: &[u32], table_bits: usize) -> usize {
+    decode_table[..(1 << table_bits)]
+        .iter()
+        .filter(|entry| (**entry & HUFFDEC_SUBTABLE_POINTER) != 0)
+        .map(|entry| 1 << (entry & HUFFDEC_LENGTH_MASK))
+        .sum()
+}
+
 /* Mask for extracting the codeword length from a decode table entry.  */
 pub const HUFFDEC_LENGTH_MASK: u32 = 0xFF;
 
@@ -331,6 +442,21 @@ pub const HUFFDEC_RESULT_SHIFT: usize = 8;
 /* Shift a decode result into its position in the decode table entry.  */
 #[inline(always)]
 const fn huffdec_result_entry(result: u32) -> u32 {
+    debug_assert!(result < (HUFFDEC_LITERAL >> HUFFDEC_RESULT_SHIFT));
+    result << HUFFDEC_RESULT_SHIFT
+}
+
+/*
+ * huffdec_result_entry() for the entry functions of the decode result tables
+ * below.  They are only evaluated in the table initializers, at compile time,
+ * so a failing assert there is a compile error instead of corrupt decoding.
+ */
+#[inline(always)]
+const fn table_result_entry(result: u32) -> u32 {
+    assert!(
+        result < (HUFFDEC_LITERAL >> HUFFDEC_RESULT_SHIFT),
+        "decode result overflows into the flags"
+    );
     result << HUFFDEC_RESULT_SHIFT
 }
 
@@ -338,7 +464,11 @@ const fn huffdec_result_entry(result: u32) -> u32 {
  * for the precode; the decode result is simply the symbol value.  */
 #[inline(always)]
 const fn hr_entry(presym: u32) -> u32 {
-    huffdec_result_entry(presym)
+    assert!(
+        (presym as usize) < DEFLATE_NUM_PRECODE_SYMS,
+        "invalid precode symbol"
+    );
+    table_result_entry(presym)
 }
 
 const PRECODE_DECODE_RESULTS: [u32; DEFLATE_NUM_PRECODE_SYMS] = [
@@ -369,7 +499,8 @@ const PRECODE_DECODE_RESULTS: [u32; DEFLATE_NUM_PRECODE_SYMS] = [
 
 #[inline(always)]
 const fn ld1_entry(literal: u32) -> u32 {
-    HUFFDEC_LITERAL | huffdec_result_entry(literal)
+    assert!(literal < 256, "invalid literal");
+    HUFFDEC_LITERAL | table_result_entry(literal)
 }
 
 pub const HUFFDEC_END_OF_BLOCK_LENGTH: u32 = 0;
@@ -379,7 +510,15 @@ pub const HUFFDEC_LENGTH_BASE_SHIFT: usize = 8;
 
 #[inline(always)]
 const fn ld2_entry(length_base: u32, num_extra_bits: u32) -> u32 {
-    huffdec_result_entry((length_base << HUFFDEC_LENGTH_BASE_SHIFT) | num_extra_bits)
+    assert!(
+        num_extra_bits as usize <= DEFLATE64_MAX_EXTRA_LENGTH_BITS,
+        "too many extra length bits"
+    );
+    assert!(
+        (length_base + (1 << num_extra_bits) - 1) as usize <= DEFLATE64_MAX_MATCH_LEN,
+        "match length out of range"
+    );
+    table_result_entry((length_base << HUFFDEC_LENGTH_BASE_SHIFT) | num_extra_bits)
 }
 
 const LITLEN_DECODE_RESULTS: [u32; DEFLATE_NUM_LITLEN_SYMS] = [
@@ -676,6 +815,13 @@ const LITLEN_DECODE_RESULTS: [u32; DEFLATE_NUM_LITLEN_SYMS] = [
     ld2_entry(258, 0),
 ];
 
+/* DEFLATE64 only differs in the last length symbol, see build_litlen_decode_table() */
+const DEFLATE64_LITLEN_DECODE_RESULTS: [u32; DEFLATE_NUM_LITLEN_SYMS] = {
+    let mut results = LITLEN_DECODE_RESULTS;
+    results[285] = ld2_entry(3, DEFLATE64_MAX_EXTRA_LENGTH_BITS as u32);
+    results
+};
+
 /* The decode result for each offset symbol.  This is the offset base and the
  * number of extra offset bits.  */
 
@@ -684,7 +830,16 @@ pub const HUFFDEC_OFFSET_BASE_MASK: u32 = (1u32 << HUFFDEC_EXTRA_OFFSET_BITS_SHI
 
 #[inline(always)]
 const fn odr_entry(offset_base: u32, num_extra_bits: u32) -> u32 {
-    huffdec_result_entry((num_extra_bits << HUFFDEC_EXTRA_OFFSET_BITS_SHIFT) | offset_base)
+    assert!(
+        num_extra_bits as usize <= DEFLATE_MAX_EXTRA_OFFSET_BITS,
+        "too many extra offset bits"
+    );
+    assert!(
+        offset_base >= 1
+            && (offset_base + (1 << num_extra_bits) - 1) as usize <= DEFLATE64_MAX_WINDOW_SIZE,
+        "match offset out of range"
+    );
+    table_result_entry((num_extra_bits << HUFFDEC_EXTRA_OFFSET_BITS_SHIFT) | offset_base)
 }
 
 const OFFSET_DECODE_RESULTS: [u32; DEFLATE_NUM_OFFSET_SYMS] = [
@@ -743,9 +898,9 @@ const fn bsr32(val: u32) -> u32 {
  *	An array which provides, for each symbol, the length of the
  *	corresponding codeword in bits, or 0 if the symbol is unused.  This may
  *	alias @decode_table, since nothing is written to @decode_table until all
- *	@lens have been consumed.  All codeword lengths are assumed to be <=
- *	@max_codeword_len but are otherwise considered untrusted.  If they do
- *	not form a valid Huffman code, then the decode table is not built and
+ *	@lens have been consumed.  All codeword lengths are considered
+ *	untrusted.  If any of them exceeds @max_codeword_len or they do not
+ *	form a valid Huffman code, then the decode table is not built and
  *	%false is returned.
  * @num_syms
  *	The number of symbols in the code, including all unused symbols.
@@ -788,6 +943,10 @@ pub fn build_decode_table(
         len_counts[len] = 0;
     }
     for sym in 0..num_syms {
+        /* Reject lengths that would not fit in 'len_counts' */
+        if unlikely(lens[sym] as usize > max_codeword_len) {
+            return false;
+        }
         len_counts[lens[sym] as usize] += 1;
     }
 
@@ -1081,11 +1240,19 @@ pub fn build_litlen_decode_table(
     /* When you change TABLEBITS, you must change ENOUGH, and vice versa! */
     const_assert!(LITLEN_TABLEBITS == 10 && LITLEN_ENOUGH == 1334);
 
+    /* The offset table already covers DEFLATE64: symbols 30 and 31 are only
+     * invalid in DEFLATE because they reach past the 32 KB window.  */
+    let decode_results = if d.deflate64 {
+        &DEFLATE64_LITLEN_DECODE_RESULTS
+    } else {
+        &LITLEN_DECODE_RESULTS
+    };
+
     return build_decode_table(
         &mut d.litlen_decode_table,
         &mut d.l.lens[..],
         num_litlen_syms,
-        &LITLEN_DECODE_RESULTS,
+        decode_results,
         LITLEN_TABLEBITS,
         DEFLATE_MAX_LITLEN_CODEWORD_LEN,
         d.sorted_syms.as_mut_ptr(),
@@ -1170,5 +1337,55 @@ pub fn libdeflate_deflate_decompress<I: DeflateInput, O: DeflateOutput>(
     in_stream: &mut I,
     out_stream: &mut O,
 ) -> Result<(), LibdeflateError> {
-    deflate_decompress_template(d, in_stream, out_stream)
+    libdeflate_deflate_decompress_on_block(d, in_stream, out_stream, |_| {})
+}
+
+/* Like libdeflate_deflate_decompress(), calling 'on_block' before each block */
+pub fn libdeflate_deflate_decompress_on_block<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+    on_block: impl FnMut(&DecodeCursor),
+) -> Result<(), LibdeflateError> {
+    let result = deflate_decompress_stream(d, in_stream, out_stream, on_block);
+    report_error(d, result)
+}
+
+/* libdeflate_deflate_decompress_on_block() without reporting errors, for the
+ * wrapper formats that report their own */
+pub(crate) fn deflate_decompress_stream<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+    on_block: impl FnMut(&DecodeCursor),
+) -> Result<(), LibdeflateError> {
+    deflate_decompress_template(d, in_stream, out_stream, on_block, |_, _| {})
+}
+
+/*
+ * Like libdeflate_deflate_decompress(), also returning the codeword lengths of
+ * each dynamic Huffman block, for debugging and testing.
+ */
+#[cfg(any(test, feature = "debug-info"))]
+pub fn libdeflate_deflate_decompress_with_lens<I: DeflateInput, O: DeflateOutput>(
+    d: &mut LibdeflateDecompressor,
+    in_stream: &mut I,
+    out_stream: &mut O,
+) -> Result<Vec<DynBlockLens>, LibdeflateError> {
+    let mut blocks = Vec::new();
+    let result = deflate_decompress_template(
+        d,
+        in_stream,
+        out_stream,
+        |_| {},
+        |lens, num_litlen_syms| {
+            blocks.push(DynBlockLens {
+                litlen_lens: lens[..num_litlen_syms].to_vec(),
+                offset_lens: lens[num_litlen_syms..].to_vec(),
+                num_litlen_syms,
+                num_offset_syms: lens.len() - num_litlen_syms,
+            })
+        },
+    );
+    report_error(d, result.map(|()| blocks))
 }
@@ -0,0 +1,76 @@
+/*
+ * ZIP entries compressed with method 8 (DEFLATE).
+ *
+ * The compressed data of a ZIP entry is a raw DEFLATE stream without any
+ * framing: the CRC-32 and the sizes are stored in the local file header (or
+ * in the data descriptor / central directory) instead, so they are passed in
+ * by the caller and checked after the stream has been decoded.
+ */
+
+use crate::decompress_gzip::DEFLATE_MAX_EXPANSION;
+use crate::decompress_utils::libdeflate_deflate_decompress;
+use crate::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
+use crate::streams::deflate_chunked_buffer_output::DeflateChunkedBufferOutput;
+use crate::{
+    libdeflate_alloc_decompressor, safety_check, DeflateInput, DeflateOutput, LibdeflateError,
+};
+use std::cmp::min;
+use std::io::Read;
+
+/* The compression method of ZIP entries holding raw DEFLATE data */
+pub const ZIP_METHOD_DEFLATE: u16 = 8;
+
+const ZIP_BUF_SIZE: usize = 1024 * 64;
+
+/*
+ * Decompress the raw DEFLATE data of a ZIP entry, checking it against the
+ * CRC-32 and uncompressed size from the ZIP header.  'compressed' must hold
+ * exactly the entry data ("compressed size" bytes): a stream ending before
+ * it is rejected as BadData.  Decompression stops with InsufficientSpace as
+ * soon as the output grows past 'expected_size'.
+ */
+pub fn decompress_zip_entry(
+    mut compressed: &[u8],
+    expected_crc32: u32,
+    expected_size: u32,
+) -> Result<Vec<u8>, LibdeflateError> {
+    let compressed_size = compressed.len();
+    /* Don't trust a corrupt header with the allocation size */
+    let mut decompressed = Vec::with_capacity(min(
+        expected_size as usize,
+        compressed_size.saturating_mul(DEFLATE_MAX_EXPANSION),
+    ));
+
+    let mut input_stream = DeflateChunkedBufferInput::new(|buf| compressed.read(buf), ZIP_BUF_SIZE);
+    let mut output_stream = DeflateChunkedBufferOutput::new(
+        |data| {
+            decompressed.extend_from_slice(data);
+            Ok(())
+        },
+        ZIP_BUF_SIZE,
+    );
+    output_stream.set_max_output(expected_size as usize);
+
+    let mut decompressor = libdeflate_alloc_decompressor();
+    libdeflate_deflate_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?;
+    safety_check!(input_stream.tell_stream_pos() == compressed_size);
+
+    let result = output_stream
+        .final_flush()
+        .map_err(|_| LibdeflateError::InsufficientSpace)?;
+    drop(output_stream);
+
+    if result.written != expected_size as usize {
+        return Err(LibdeflateError::LengthMismatch {
+            expected: expected_size,
+            actual: result.written as u32,
+        });
+    }
+    if result.crc32 != expected_crc32 {
+        return Err(LibdeflateError::ChecksumMismatch {
+            expected: expected_crc32,
+            actual: result.crc32,
+        });
+    }
+    Ok(decompressed)
+}
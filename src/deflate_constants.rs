@@ -47,13 +47,18 @@ pub const DEFLATE_MAX_LENS_OVERRUN: usize = 137;
 /*
  * Maximum number of extra bits that may be required to represent a match
  * length or offset.
- *
- * TODO: are we going to have full DEFLATE64 support?  If so, up to 16
- * length bits must be supported.
  */
 pub const DEFLATE_MAX_EXTRA_LENGTH_BITS: usize = 5;
 pub const DEFLATE_MAX_EXTRA_OFFSET_BITS: usize = 14;
 
+/*
+ * DEFLATE64 (PKZIP method 9) reuses length symbol 285 as base 3 with 16 extra
+ * bits, and allows offsets up to 65536 through offset symbols 30 and 31.
+ */
+pub const DEFLATE64_MAX_EXTRA_LENGTH_BITS: usize = 16;
+pub const DEFLATE64_MAX_MATCH_LEN: usize = 65538;
+pub const DEFLATE64_MAX_WINDOW_SIZE: usize = 65536;
+
 /* The maximum number of bits in which a match can be represented.  This
  * is the absolute worst case, which assumes the longest possible Huffman
  * codewords and the maximum numbers of extra bits.  */
@@ -0,0 +1,188 @@
+/*
+ * C ABI for the decompression functions of libdeflate.h, so that existing C
+ * and C++ code can link against this crate instead of libdeflate.  Build it
+ * with the "ffi" feature as a C library, e.g.
+ *
+ *   cargo rustc --release --features ffi --lib --crate-type cdylib
+ *
+ * The signatures and the result codes are the ones of libdeflate, so its
+ * header can be used unchanged for the functions exported here.
+ */
+
+use crate::decompress_gzip::gzip_decompress;
+use crate::decompress_utils::libdeflate_deflate_decompress as deflate_decompress;
+use crate::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
+use crate::streams::deflate_chunked_buffer_output::DeflateChunkedBufferOutput;
+use crate::{DeflateOutput, LibdeflateDecompressor, LibdeflateError};
+use std::io::Read;
+use std::os::raw::{c_int, c_void};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+/* enum libdeflate_result */
+pub const LIBDEFLATE_SUCCESS: c_int = 0;
+pub const LIBDEFLATE_BAD_DATA: c_int = 1;
+pub const LIBDEFLATE_SHORT_OUTPUT: c_int = 2;
+pub const LIBDEFLATE_INSUFFICIENT_SPACE: c_int = 3;
+
+const FFI_BUF_SIZE: usize = 1024 * 64;
+
+/*
+ * libdeflate only distinguishes these three failures: everything else,
+ * including the gzip header and trailer checks, is reported as bad data.
+ */
+fn result_code(error: &LibdeflateError) -> c_int {
+    match error {
+        LibdeflateError::ShortOutput => LIBDEFLATE_SHORT_OUTPUT,
+        LibdeflateError::InsufficientSpace => LIBDEFLATE_INSUFFICIENT_SPACE,
+        _ => LIBDEFLATE_BAD_DATA,
+    }
+}
+
+/* C callers may pass NULL together with a zero length */
+unsafe fn input_slice<'a>(data: *const c_void, nbytes: usize) -> &'a [u8] {
+    if nbytes == 0 {
+        &[]
+    } else {
+        from_raw_parts(data as *const u8, nbytes)
+    }
+}
+
+unsafe fn output_slice<'a>(data: *mut c_void, nbytes: usize) -> &'a mut [u8] {
+    if nbytes == 0 {
+        &mut []
+    } else {
+        from_raw_parts_mut(data as *mut u8, nbytes)
+    }
+}
+
+/*
+ * Decompress 'in' into 'out' with 'decompress', following the libdeflate
+ * conventions for 'actual_out_nbytes_ret': when it is NULL the data must
+ * fill 'out' exactly.
+ */
+unsafe fn decompress_buffer(
+    decompressor: *mut LibdeflateDecompressor,
+    in_data: *const c_void,
+    in_nbytes: usize,
+    out_data: *mut c_void,
+    out_nbytes_avail: usize,
+    actual_out_nbytes_ret: *mut usize,
+    decompress: impl FnOnce(
+        &mut LibdeflateDecompressor,
+        &mut DeflateChunkedBufferInput,
+        &mut DeflateChunkedBufferOutput,
+    ) -> Result<usize, LibdeflateError>,
+) -> c_int {
+    let mut in_data = input_slice(in_data, in_nbytes);
+    let out_data = output_slice(out_data, out_nbytes_avail);
+    let mut out_pos = 0;
+
+    let mut input_stream = DeflateChunkedBufferInput::new(|buf| in_data.read(buf), FFI_BUF_SIZE);
+    let mut output_stream = DeflateChunkedBufferOutput::new(
+        |data| {
+            out_data[out_pos..out_pos + data.len()].copy_from_slice(data);
+            out_pos += data.len();
+            Ok(())
+        },
+        FFI_BUF_SIZE,
+    );
+    output_stream.set_max_output(out_nbytes_avail);
+
+    let written = match decompress(&mut *decompressor, &mut input_stream, &mut output_stream) {
+        Ok(written) => written,
+        Err(error) => return result_code(&error),
+    };
+
+    if actual_out_nbytes_ret.is_null() {
+        if written != out_nbytes_avail {
+            return LIBDEFLATE_SHORT_OUTPUT;
+        }
+    } else {
+        *actual_out_nbytes_ret = written;
+    }
+    LIBDEFLATE_SUCCESS
+}
+
+/*
+ * Allocate a decompressor, to be released with libdeflate_free_decompressor().
+ * Unlike libdeflate, running out of memory aborts instead of returning NULL.
+ */
+#[no_mangle]
+pub extern "C" fn libdeflate_alloc_decompressor() -> *mut LibdeflateDecompressor {
+    Box::into_raw(Box::new(crate::libdeflate_alloc_decompressor()))
+}
+
+/* Decompress the raw DEFLATE stream in 'in', see libdeflate.h. */
+/// # Safety
+///
+/// 'decompressor' must come from libdeflate_alloc_decompressor(), 'in' and
+/// 'out' must be valid for 'in_nbytes' and 'out_nbytes_avail' bytes, and
+/// 'actual_out_nbytes_ret' must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn libdeflate_deflate_decompress(
+    decompressor: *mut LibdeflateDecompressor,
+    in_data: *const c_void,
+    in_nbytes: usize,
+    out_data: *mut c_void,
+    out_nbytes_avail: usize,
+    actual_out_nbytes_ret: *mut usize,
+) -> c_int {
+    decompress_buffer(
+        decompressor,
+        in_data,
+        in_nbytes,
+        out_data,
+        out_nbytes_avail,
+        actual_out_nbytes_ret,
+        |d, in_stream, out_stream| {
+            deflate_decompress(d, in_stream, out_stream)?;
+            out_stream
+                .final_flush()
+                .map(|result| result.written)
+                .map_err(|_| LibdeflateError::InsufficientSpace)
+        },
+    )
+}
+
+/*
+ * Decompress the first gzip member in 'in', see libdeflate.h.  Any data
+ * following the member is ignored.
+ */
+/// # Safety
+///
+/// Same as libdeflate_deflate_decompress().
+#[no_mangle]
+pub unsafe extern "C" fn libdeflate_gzip_decompress(
+    decompressor: *mut LibdeflateDecompressor,
+    in_data: *const c_void,
+    in_nbytes: usize,
+    out_data: *mut c_void,
+    out_nbytes_avail: usize,
+    actual_out_nbytes_ret: *mut usize,
+) -> c_int {
+    decompress_buffer(
+        decompressor,
+        in_data,
+        in_nbytes,
+        out_data,
+        out_nbytes_avail,
+        actual_out_nbytes_ret,
+        |d, in_stream, out_stream| {
+            gzip_decompress(d, in_stream, out_stream).map(|result| result.written)
+        },
+    )
+}
+
+/*
+ * Free a decompressor allocated with libdeflate_alloc_decompressor(), NULL is
+ * ignored.
+ */
+/// # Safety
+///
+/// 'decompressor' must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn libdeflate_free_decompressor(decompressor: *mut LibdeflateDecompressor) {
+    if !decompressor.is_null() {
+        drop(Box::from_raw(decompressor));
+    }
+}
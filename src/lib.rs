@@ -1,11 +1,19 @@
 #![cfg_attr(debug_assertions, deny(warnings))]
+pub mod adler32;
+pub mod decompress_bgzf;
+pub mod decompress_chunks;
 pub mod decompress_deflate;
 pub mod decompress_gzip;
 mod decompress_utils;
+pub mod decompress_zip;
 mod deflate_constants;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod gzip_constants;
 pub mod streams;
 mod utils;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 #[macro_use]
 extern crate static_assertions;
@@ -14,16 +22,25 @@ extern crate static_assertions;
 extern crate mt_debug_counters;
 
 use crate::decompress_deflate::{
-    LenType, OutStreamResult, _DecStruct, LITLEN_ENOUGH, OFFSET_ENOUGH,
+    LenType, OutStreamResult, _DecStruct, LITLEN_ENOUGH, LITLEN_TABLEBITS, OFFSET_ENOUGH,
+    OFFSET_TABLEBITS,
 };
-use crate::decompress_gzip::libdeflate_gzip_decompress;
+use crate::decompress_gzip::{
+    at_gzip_member, gzip_decompress, libdeflate_gzip_decompress_with_header, skip_to_gzip_member,
+    GzipHeader,
+};
+use crate::decompress_utils::{check_input_error, subtable_entries_used};
 use crate::deflate_constants::{DEFLATE_MAX_NUM_SYMS, DEFLATE_NUM_PRECODE_SYMS};
 use crate::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
 use crate::streams::deflate_chunked_buffer_output::DeflateChunkedBufferOutput;
+use crate::streams::deflate_line_output::DeflateLineOutput;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::mem::{size_of, MaybeUninit};
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 /*
  * The main DEFLATE decompressor structure.  Since this implementation only
@@ -41,6 +58,98 @@ pub struct LibdeflateDecompressor {
     /* used only during build_decode_table() */
     pub(crate) sorted_syms: [u16; DEFLATE_MAX_NUM_SYMS],
     pub(crate) static_codes_loaded: bool,
+    pub(crate) deflate64: bool,
+    /* Accept gzip headers with reserved FLG bits set, see set_strict_mode() */
+    pub(crate) allow_reserved_flags: bool,
+    #[cfg(feature = "stats")]
+    pub(crate) stats: crate::decompress_deflate::BlockStats,
+    #[cfg(feature = "callbacks")]
+    pub(crate) callbacks: crate::decompress_deflate::DecompressCallbacks,
+}
+
+/* Memory taken by a decompressor and its decode tables, see memory_usage() */
+#[derive(Clone, Debug)]
+pub struct DecodeTableMemory {
+    pub static_bytes: usize,
+    pub litlen_subtable_entries_used: usize,
+    pub litlen_subtable_capacity: usize,
+    pub offset_subtable_entries_used: usize,
+    pub offset_subtable_capacity: usize,
+}
+
+impl fmt::Display for DecodeTableMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes, litlen subtables {}/{} entries, offset subtables {}/{} entries",
+            self.static_bytes,
+            self.litlen_subtable_entries_used,
+            self.litlen_subtable_capacity,
+            self.offset_subtable_entries_used,
+            self.offset_subtable_capacity
+        )
+    }
+}
+
+impl LibdeflateDecompressor {
+    /*
+     * Report the size of the decompressor and how much of the subtable
+     * regions is used by the decode tables built for the last block.
+     */
+    pub fn memory_usage(&self) -> DecodeTableMemory {
+        DecodeTableMemory {
+            static_bytes: size_of::<Self>(),
+            litlen_subtable_entries_used: subtable_entries_used(
+                &self.litlen_decode_table,
+                LITLEN_TABLEBITS,
+            ),
+            litlen_subtable_capacity: LITLEN_ENOUGH - (1 << LITLEN_TABLEBITS),
+            offset_subtable_entries_used: subtable_entries_used(
+                &self.offset_decode_table,
+                OFFSET_TABLEBITS,
+            ),
+            offset_subtable_capacity: OFFSET_ENOUGH - (1 << OFFSET_TABLEBITS),
+        }
+    }
+
+    /*
+     * Forget the decode tables built for the previous stream.  This is never
+     * needed for correctness: the static tables are only reused while no
+     * dynamic block has overwritten them, and the tables of dynamic blocks
+     * are always rebuilt.
+     */
+    pub fn reset(&mut self) {
+        self.static_codes_loaded = false;
+    }
+
+    /*
+     * RFC 1952 requires the reserved FLG bits 5-7 of gzip headers to be zero,
+     * and by default members setting them fail with ReservedFlags.  Some
+     * producers set them anyway: with 'strict' false they are ignored.
+     */
+    pub fn set_strict_mode(&mut self, strict: bool) {
+        self.allow_reserved_flags = !strict;
+    }
+
+    /*
+     * Call 'on_block_done' after each DEFLATE block is decoded, with the
+     * offset in bits of its header in the input stream, its BTYPE and its
+     * decompressed size, if the output tracks it.  See DecompressCallbacks
+     * for what the callbacks may do.
+     */
+    #[cfg(feature = "callbacks")]
+    pub fn set_on_block_done(
+        &mut self,
+        on_block_done: impl Fn(u64, u32, Option<usize>) + Send + Sync + 'static,
+    ) {
+        self.callbacks.on_block_done = Some(Box::new(on_block_done));
+    }
+
+    /* Call 'on_error' with each error before it is returned */
+    #[cfg(feature = "callbacks")]
+    pub fn set_on_error(&mut self, on_error: impl Fn(&LibdeflateError) + Send + Sync + 'static) {
+        self.callbacks.on_error = Some(Box::new(on_error));
+    }
 }
 
 /*
@@ -51,27 +160,108 @@ pub struct LibdeflateDecompressor {
 pub enum LibdeflateError {
     /* Decompressed failed because the compressed data was invalid, corrupt,
      * or otherwise unsupported.  */
-    BadData = 1,
+    BadData,
 
     /* A NULL 'actual_out_nbytes_ret' was provided, but the data would have
      * decompressed to fewer than 'out_nbytes_avail' bytes.  */
-    ShortOutput = 2,
+    ShortOutput,
 
     /* The data would have decompressed to more than 'out_nbytes_avail'
      * bytes.  */
-    InsufficientSpace = 3,
+    InsufficientSpace,
+
+    /* The gzip header doesn't start with the ID1/ID2 magic bytes.  */
+    BadMagic,
+
+    /* The gzip header uses a compression method other than DEFLATE.  */
+    UnsupportedMethod,
+
+    /* The gzip header has some of the reserved flag bits set.  */
+    ReservedFlags,
+
+    /* The gzip header CRC16 (FHCRC) doesn't match the header bytes.  */
+    HeaderCrcMismatch,
+
+    /* The codeword lengths of a block don't form a valid Huffman code.  */
+    InvalidHuffmanCode,
+
+    /* The LEN and NLEN fields of a stored block don't match, 'bit_position'
+     * is the offset in bits of the block header in the input stream.  */
+    BadStoredBlock {
+        len: u16,
+        nlen: u16,
+        bit_position: u64,
+    },
+
+    /* The gzip trailer CRC32 doesn't match the decompressed data.  */
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+
+    /* The gzip trailer ISIZE doesn't match the decompressed size (modulo
+     * 2^32), usually a sign of a truncated file.  */
+    LengthMismatch {
+        expected: u32,
+        actual: u32,
+    },
+
+    /* The header callback of libdeflate_gzip_decompress_on_header() rejected
+     * the member.  */
+    HeaderRejected,
+
+    /* The input ended before the end of the stream, after
+     * 'compressed_bytes_processed' bytes, e.g. a partially received file.  */
+    Truncated {
+        compressed_bytes_processed: u64,
+    },
+
+    /* Reading the compressed data failed.  */
+    ReadError(std::io::Error),
+
+    /* Writing the decompressed data failed.  */
+    WriteError(std::io::Error),
+
+    /* The decompression thread of DecompressChunks panicked.  */
+    WorkerPanicked,
 }
 
 pub trait DeflateInput {
-    const MAX_LOOK_BACK: usize = size_of::<usize>();
+    /* Unused bytes of the bitbuffer are given back at the end of a block,
+     * which is 8 bytes on 32-bit targets too */
+    const MAX_LOOK_BACK: usize = size_of::<u64>();
 
     unsafe fn get_le_word_no_advance(&mut self) -> usize;
+    /* Offset in the stream of the next byte to be read */
+    fn tell_stream_pos(&self) -> usize;
     fn move_stream_pos(&mut self, amount: isize) -> bool;
     fn read(&mut self, out_data: &mut [u8]) -> usize;
     fn ensure_length(&mut self, len: usize) -> bool;
     unsafe fn read_unchecked(&mut self, out_data: &mut [u8]);
     fn read_exact_into<O: DeflateOutput>(&mut self, out_stream: &mut O, length: usize) -> bool;
 
+    /* Returns the error that made the underlying reader stop, if any.  A
+     * failed read otherwise looks like the end of the stream.  */
+    #[inline(always)]
+    fn take_read_error(&mut self) -> Option<std::io::Error> {
+        None
+    }
+
+    /* True if the input is known to have no data left, without reading.
+     * Inputs that refill only find out once a refill hits the end of the
+     * stream, so false means that ensure_length() has to be asked.  */
+    #[inline(always)]
+    fn is_at_eof(&self) -> bool {
+        false
+    }
+
+    /* Fill 'out_data' entirely, false if the input ended before.  read()
+     * already refills as needed, so a short count only happens at the end. */
+    #[inline(always)]
+    fn read_exact(&mut self, out_data: &mut [u8]) -> bool {
+        self.read(out_data) == out_data.len()
+    }
+
     #[inline(always)]
     fn read_byte(&mut self) -> u8 {
         let mut byte = [0];
@@ -102,6 +292,26 @@ pub trait DeflateOutput {
     fn get_available_buffer(&mut self) -> &mut [u8];
     unsafe fn advance_available_buffer_position(&mut self, offset: usize);
     fn final_flush(&mut self) -> Result<OutStreamResult, ()>;
+
+    /* Whether a write failed because the output reached its size limit, the
+     * decoder then reports InsufficientSpace instead of BadData.  */
+    #[inline(always)]
+    fn is_quota_exceeded(&self) -> bool {
+        false
+    }
+
+    /* Bytes written since the start of the member, if the output tracks it */
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        None
+    }
+
+    /* Returns the error that made the underlying writer fail, if any.  A
+     * failed write otherwise looks like a full output.  */
+    #[inline(always)]
+    fn take_write_error(&mut self) -> Option<std::io::Error> {
+        None
+    }
 }
 
 pub fn libdeflate_alloc_decompressor() -> LibdeflateDecompressor {
@@ -119,38 +329,3161 @@ pub fn libdeflate_alloc_decompressor() -> LibdeflateDecompressor {
      *   all entries eventually anyway.)
      *
      * But for simplicity, we currently just zero the whole decompressor.
+     * Only the boxed callbacks need a proper initialization.
+     */
+    #[cfg_attr(not(feature = "callbacks"), allow(unused_mut))]
+    let mut d = MaybeUninit::<LibdeflateDecompressor>::zeroed();
+    #[cfg(feature = "callbacks")]
+    unsafe {
+        std::ptr::addr_of_mut!((*d.as_mut_ptr()).callbacks)
+            .write(crate::decompress_deflate::DecompressCallbacks::default());
+    }
+    unsafe { d.assume_init() }
+}
+
+/*
+ * Allocate a decompressor for DEFLATE64 (PKZIP method 9) raw streams, to be
+ * used with libdeflate_deflate_decompress().  Matches may reach back 64 KB and
+ * be up to DEFLATE64_MAX_MATCH_LEN bytes long, so the output must keep a
+ * DEFLATE64_MAX_WINDOW_SIZE window, e.g.
+ * DeflateChunkedBufferOutput::new_deflate64().
+ */
+pub fn libdeflate_alloc_deflate64_decompressor() -> LibdeflateDecompressor {
+    let mut d = libdeflate_alloc_decompressor();
+    d.deflate64 = true;
+    d
+}
+
+/*
+ * Decompressors to be shared by threads decompressing many files, e.g. with
+ * rayon, so that each task doesn't allocate and zero its own decode tables.
+ * At most 'max_idle' released decompressors are kept, the others are freed.
+ */
+pub struct DecompressorPool {
+    decompressors: Mutex<Vec<Box<LibdeflateDecompressor>>>,
+    max_idle: usize,
+}
+
+impl DecompressorPool {
+    pub fn new(max_idle: usize) -> Self {
+        Self {
+            decompressors: Mutex::new(Vec::with_capacity(max_idle)),
+            max_idle,
+        }
+    }
+
+    /* A released decompressor, or a new one if none is left */
+    pub fn acquire(&self) -> Box<LibdeflateDecompressor> {
+        let decompressor = self.decompressors.lock().unwrap().pop();
+        decompressor.unwrap_or_else(|| Box::new(libdeflate_alloc_decompressor()))
+    }
+
+    /*
+     * Give back a decompressor obtained from acquire().  Its settings are kept
+     * for the next user, so it shouldn't be released after changing them,
+     * e.g. with set_strict_mode().
      */
-    unsafe { MaybeUninit::<LibdeflateDecompressor>::zeroed().assume_init() }
+    pub fn release(&self, decompressor: Box<LibdeflateDecompressor>) {
+        let mut decompressors = self.decompressors.lock().unwrap();
+        if decompressors.len() < self.max_idle {
+            decompressors.push(decompressor);
+        }
+    }
+
+    /* Number of released decompressors waiting to be reused */
+    pub fn idle_count(&self) -> usize {
+        self.decompressors.lock().unwrap().len()
+    }
+}
+
+/* Summary of a decompress_file_buffered() run */
+#[derive(Clone, Debug)]
+pub struct FileDecompressStats {
+    pub members_processed: usize,
+    pub compressed_bytes: u64,
+    pub decompressed_bytes: u64,
+    pub duration: Duration,
+    /* Decompressed megabytes (10^6 bytes) per second */
+    pub throughput_mb_per_s: f64,
+    /* Bytes found after the last gzip member */
+    pub trailing_bytes: u64,
+    /* The trailing bytes, with TrailingPolicy::Return */
+    pub trailing_data: Option<Vec<u8>>,
+    /* Block statistics of OutStreamResult summed over the members, only
+     * filled in with the "stats" feature */
+    pub dynamic_blocks: u32,
+    pub static_blocks: u32,
+    pub uncompressed_blocks: u32,
+    pub total_symbols: u64,
+}
+
+/* What to do with data following the last gzip member of a file */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingPolicy {
+    /* Try to decode it as another member, failing if it isn't one */
+    Reject,
+    /* Skip it, e.g. zero padding added by tape or block devices */
+    #[default]
+    Ignore,
+    /* Skip it and return it in FileDecompressStats::trailing_data */
+    Return,
+}
+
+impl FileDecompressStats {
+    /* Decompressed size over compressed size */
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 0.0;
+        }
+        self.decompressed_bytes as f64 / self.compressed_bytes as f64
+    }
 }
 
 pub fn decompress_file_buffered(
     file: impl AsRef<Path>,
     func: impl FnMut(&[u8]) -> Result<(), ()>,
     buf_size: usize,
-) -> Result<(), LibdeflateError> {
-    let mut read_file = File::open(file).unwrap();
+) -> Result<FileDecompressStats, LibdeflateError> {
+    decompress_file_buffered_members(file, func, |_, _| {}, buf_size, TrailingPolicy::Ignore)
+}
+
+/*
+ * Like decompress_file_buffered(), calling 'on_member_end' after each gzip
+ * member with its header and result.  All the data of a member has been
+ * passed to 'func' by then, so per-member state can be reset there.
+ * 'trailing' selects how data that isn't a gzip member is handled once at
+ * least one member has been decoded.
+ */
+pub fn decompress_file_buffered_members(
+    file: impl AsRef<Path>,
+    func: impl FnMut(&[u8]) -> Result<(), ()>,
+    on_member_end: impl FnMut(&GzipHeader, &OutStreamResult),
+    buf_size: usize,
+    trailing: TrailingPolicy,
+) -> Result<FileDecompressStats, LibdeflateError> {
+    decompress_file_members(
+        &mut libdeflate_alloc_decompressor(),
+        file,
+        func,
+        on_member_end,
+        buf_size,
+        trailing,
+        None,
+    )
+}
 
-    let mut input_stream =
-        DeflateChunkedBufferInput::new(|buf| read_file.read(buf).unwrap_or(0), buf_size);
+/*
+ * Like decompress_file_buffered(), decoding with 'decompressor' instead of
+ * allocating one, e.g. taken from a DecompressorPool.
+ */
+pub fn decompress_file_buffered_with_decompressor(
+    decompressor: &mut LibdeflateDecompressor,
+    file: impl AsRef<Path>,
+    func: impl FnMut(&[u8]) -> Result<(), ()>,
+    buf_size: usize,
+) -> Result<FileDecompressStats, LibdeflateError> {
+    decompress_file_members(
+        decompressor,
+        file,
+        func,
+        |_, _| {},
+        buf_size,
+        TrailingPolicy::Ignore,
+        None,
+    )
+}
+
+/* Outcome of a gzip member decoded by decompress_file_buffered_recover() */
+#[derive(Debug)]
+pub struct MemberOutcome {
+    /* Offset of the member in the compressed file */
+    pub offset: u64,
+    /* Decompressed size of the member, or why it couldn't be decoded */
+    pub result: Result<usize, LibdeflateError>,
+}
+
+/*
+ * Like decompress_file_buffered(), but salvages as much data as possible from
+ * damaged files: when a member fails to decode, the data following it is
+ * searched for the next gzip magic and decompression continues from there.
+ * The output of a failed member is passed to 'func' up to the point where the
+ * error was detected.  A member whose start was consumed while decoding the
+ * previous, corrupt, one is lost.  Other data found between members is
+ * reported as a BadMagic outcome, or as trailing bytes at the end of the file.
+ * Only output and read errors are returned.
+ */
+pub fn decompress_file_buffered_recover(
+    file: impl AsRef<Path>,
+    func: impl FnMut(&[u8]) -> Result<(), ()>,
+    buf_size: usize,
+) -> Result<(FileDecompressStats, Vec<MemberOutcome>), LibdeflateError> {
+    let mut outcomes = Vec::new();
+    let stats = decompress_file_members(
+        &mut libdeflate_alloc_decompressor(),
+        file,
+        func,
+        |_, _| {},
+        buf_size,
+        TrailingPolicy::Ignore,
+        Some(&mut outcomes),
+    )?;
+    Ok((stats, outcomes))
+}
+
+fn decompress_file_members(
+    decompressor: &mut LibdeflateDecompressor,
+    file: impl AsRef<Path>,
+    func: impl FnMut(&[u8]) -> Result<(), ()>,
+    mut on_member_end: impl FnMut(&GzipHeader, &OutStreamResult),
+    buf_size: usize,
+    trailing: TrailingPolicy,
+    mut recover: Option<&mut Vec<MemberOutcome>>,
+) -> Result<FileDecompressStats, LibdeflateError> {
+    let start = Instant::now();
+    let mut read_file = File::open(file).map_err(LibdeflateError::ReadError)?;
+
+    let mut input_stream = DeflateChunkedBufferInput::new(|buf| read_file.read(buf), buf_size);
 
     let mut output_stream = DeflateChunkedBufferOutput::new(func, buf_size);
 
+    let mut header = GzipHeader::default();
+    let mut members_processed = 0;
+    let mut trailing_bytes = 0;
+    let mut trailing_data = None;
+    let mut totals = OutStreamResult::default();
+    while input_stream.ensure_length(1) {
+        if let Some(outcomes) = recover.as_deref_mut() {
+            if !at_gzip_member(&mut input_stream) {
+                let offset = input_stream.tell_stream_pos() as u64;
+                let skipped = skip_to_gzip_member(&mut input_stream);
+                if !input_stream.ensure_length(1) {
+                    trailing_bytes += skipped as u64;
+                    break;
+                }
+                outcomes.push(MemberOutcome {
+                    offset,
+                    result: Err(LibdeflateError::BadMagic),
+                });
+            }
+        }
+
+        if members_processed > 0
+            && trailing != TrailingPolicy::Reject
+            && !at_gzip_member(&mut input_stream)
+        {
+            let mut data = Vec::new();
+            let mut chunk = [0; 4096];
+            loop {
+                let read = input_stream.read(&mut chunk);
+                if read == 0 {
+                    break;
+                }
+                trailing_bytes += read as u64;
+                if trailing == TrailingPolicy::Return {
+                    data.extend_from_slice(&chunk[..read]);
+                }
+            }
+            if trailing == TrailingPolicy::Return {
+                trailing_data = Some(data);
+            }
+            break;
+        }
+
+        let offset = input_stream.tell_stream_pos() as u64;
+        let result = libdeflate_gzip_decompress_with_header(
+            decompressor,
+            &mut input_stream,
+            &mut output_stream,
+            &mut header,
+        );
+        match result {
+            Ok(result) => {
+                members_processed += 1;
+                totals.dynamic_blocks += result.dynamic_blocks;
+                totals.static_blocks += result.static_blocks;
+                totals.uncompressed_blocks += result.uncompressed_blocks;
+                totals.total_symbols += result.total_symbols;
+                on_member_end(&header, &result);
+                if let Some(outcomes) = recover.as_deref_mut() {
+                    outcomes.push(MemberOutcome {
+                        offset,
+                        result: Ok(result.written),
+                    });
+                }
+            }
+            Err(err) => {
+                /* Output and read errors would affect the following members too */
+                let outcomes = match recover.as_deref_mut() {
+                    Some(outcomes)
+                        if !matches!(
+                            err,
+                            LibdeflateError::InsufficientSpace
+                                | LibdeflateError::ReadError(_)
+                                | LibdeflateError::WriteError(_)
+                        ) =>
+                    {
+                        outcomes
+                    }
+                    _ => return Err(err),
+                };
+                outcomes.push(MemberOutcome {
+                    offset,
+                    result: Err(err),
+                });
+
+                /* Flush what was decoded of the failed member, resetting the output */
+                output_stream
+                    .final_flush()
+                    .map_err(|_| LibdeflateError::InsufficientSpace)?;
+                skip_to_gzip_member(&mut input_stream);
+            }
+        }
+    }
+    check_input_error(&mut input_stream)?;
+
+    let compressed_bytes = input_stream.take_telemetry().total_bytes_read;
+    let decompressed_bytes = output_stream.bytes_written_so_far() as u64;
+
+    let duration = start.elapsed();
+    Ok(FileDecompressStats {
+        members_processed,
+        compressed_bytes,
+        decompressed_bytes,
+        duration,
+        throughput_mb_per_s: decompressed_bytes as f64 / 1_000_000.0 / duration.as_secs_f64(),
+        trailing_bytes,
+        trailing_data,
+        dynamic_blocks: totals.dynamic_blocks,
+        static_blocks: totals.static_blocks,
+        uncompressed_blocks: totals.uncompressed_blocks,
+        total_symbols: totals.total_symbols,
+    })
+}
+
+/*
+ * Decompress the gzip stream produced by 'source' one byte at a time, passing
+ * the decompressed data to 'data_cb' in chunks of up to 'buf_size' bytes.
+ * Data after the last member is ignored.
+ */
+pub fn decompress_gzip_from_iter<I: Iterator<Item = u8>>(
+    source: I,
+    data_cb: impl FnMut(&[u8]) -> Result<(), ()>,
+    buf_size: usize,
+) -> Result<(), LibdeflateError> {
+    let mut input_stream = DeflateChunkedBufferInput::new_from_iter(source, buf_size);
+    let mut output_stream = DeflateChunkedBufferOutput::new(data_cb, buf_size);
+
     let mut decompressor = libdeflate_alloc_decompressor();
 
+    let mut members_processed = 0;
     while input_stream.ensure_length(1) {
-        libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?
+        if members_processed > 0 && !at_gzip_member(&mut input_stream) {
+            break;
+        }
+        gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?;
+        members_processed += 1;
     }
     Ok(())
 }
 
+/*
+ * Decompress a gzip file passing it to 'line_cb' one line at a time, see
+ * DeflateLineOutput.  Returns the number of lines.
+ */
+pub fn decompress_gzip_lines<P: AsRef<Path>>(
+    path: P,
+    line_cb: impl FnMut(&[u8]) -> Result<(), ()>,
+) -> Result<usize, LibdeflateError> {
+    let mut read_file = File::open(path).map_err(LibdeflateError::ReadError)?;
+
+    let mut input_stream = DeflateChunkedBufferInput::new(|buf| read_file.read(buf), 1024 * 512);
+    let mut output_stream = DeflateLineOutput::new(line_cb);
+
+    let mut decompressor = libdeflate_alloc_decompressor();
+
+    let mut members_processed = 0;
+    while input_stream.ensure_length(1) {
+        if members_processed > 0 && !at_gzip_member(&mut input_stream) {
+            break;
+        }
+        gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?;
+        members_processed += 1;
+    }
+    check_input_error(&mut input_stream)?;
+
+    output_stream.finish()
+}
+
+/*
+ * Like decompress_file_buffered(), also returning the digest of the whole
+ * decompressed data, e.g. with H = sha2::Sha256.
+ */
+#[cfg(feature = "sha2")]
+pub fn decompress_file_buffered_hashed<H: sha2::Digest>(
+    file: impl AsRef<Path>,
+    mut func: impl FnMut(&[u8]) -> Result<(), ()>,
+    buf_size: usize,
+) -> Result<(FileDecompressStats, Vec<u8>), LibdeflateError> {
+    let mut hasher = H::new();
+    let stats = decompress_file_buffered(
+        file,
+        |data| {
+            hasher.update(data);
+            func(data)
+        },
+        buf_size,
+    )?;
+    Ok((stats, hasher.finalize().to_vec()))
+}
+
+/* Header and digest of each member, see decompress_file_buffered_per_member_hash() */
+#[cfg(feature = "sha2")]
+pub type MemberDigests = Vec<(GzipHeader, Vec<u8>)>;
+
+/*
+ * Like decompress_file_buffered(), also returning the header and the digest
+ * of the decompressed data of each gzip member, in file order.
+ */
+#[cfg(feature = "sha2")]
+pub fn decompress_file_buffered_per_member_hash<H: sha2::Digest>(
+    file: impl AsRef<Path>,
+    mut func: impl FnMut(&[u8]) -> Result<(), ()>,
+    buf_size: usize,
+) -> Result<(FileDecompressStats, MemberDigests), LibdeflateError> {
+    let hasher = std::cell::RefCell::new(H::new());
+    let mut digests = Vec::new();
+    let stats = decompress_file_buffered_members(
+        file,
+        |data| {
+            hasher.borrow_mut().update(data);
+            func(data)
+        },
+        |header, _| {
+            let member_hasher = std::mem::replace(&mut *hasher.borrow_mut(), H::new());
+            digests.push((header.clone(), member_hasher.finalize().to_vec()))
+        },
+        buf_size,
+        TrailingPolicy::Ignore,
+    )?;
+    Ok((stats, digests))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::decompress_file_buffered;
+    use crate::adler32::{adler32, Adler32};
+    use crate::decompress_bgzf::decompress_bgzf_file;
+    use crate::decompress_chunks::DecompressChunks;
+    use crate::decompress_deflate::{
+        libdeflate_deflate_decompress, libdeflate_deflate_decompress_with_lens, verify_precode,
+        DEFLATE64_MAX_MATCH_LEN, DEFLATE64_MAX_WINDOW_SIZE, DEFLATE_NUM_PRECODE_SYMS,
+    };
+    use crate::decompress_gzip::{
+        gunzip_to, gunzip_to_vec, gzip_decompress, gzip_isize_hint, gzip_isize_hint_seek,
+        libdeflate_gzip_decompress, libdeflate_gzip_decompress_on_block,
+        libdeflate_gzip_decompress_on_header, libdeflate_gzip_decompress_with_header, GzipHeader,
+        GzipSliceDecoder, GZIP_MAX_STRING_LEN,
+    };
+    use crate::decompress_utils::{
+        build_litlen_decode_table, build_offset_decode_table, build_precode_decode_table,
+    };
+    use crate::decompress_zip::{decompress_zip_entry, ZIP_METHOD_DEFLATE};
+    use crate::deflate_constants::{
+        DEFLATE_MAX_LITLEN_CODEWORD_LEN, DEFLATE_MAX_OFFSET_CODEWORD_LEN, DEFLATE_NUM_LITLEN_SYMS,
+        DEFLATE_NUM_OFFSET_SYMS,
+    };
+    use crate::gzip_constants::{GZIP_FHCRC, GZIP_FRESERVED, GZIP_FTEXT, GZIP_MIN_HEADER_SIZE};
+    use crate::streams::deflate_anon_mem_input::DeflateAnonMemInput;
+    use crate::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
+    use crate::streams::deflate_chunked_buffer_output::{
+        DeflateChunkedBufferOutput, DeflateSliceSink,
+    };
+    use crate::streams::deflate_line_output::DeflateLineOutput;
+    use crate::streams::deflate_ring_buffer_input::DeflateRingBufferInput;
+    use crate::streams::deflate_ring_output::DeflateRingOutput;
+    use crate::streams::deflate_tee_output::DeflateTeeOutput;
+    use crate::streams::deflate_verify_output::DeflateVerifyOutput;
+    use crate::streams::deflate_write_output::DeflateWriteOutput;
+    use crate::{
+        decompress_file_buffered, decompress_file_buffered_members,
+        decompress_file_buffered_recover, decompress_file_buffered_with_decompressor,
+        decompress_gzip_from_iter, decompress_gzip_lines, DecompressorPool, TrailingPolicy,
+    };
+    use crate::{
+        libdeflate_alloc_decompressor, libdeflate_alloc_deflate64_decompressor,
+        LibdeflateDecompressor,
+    };
+    use crate::{DeflateInput, DeflateOutput, LibdeflateError};
     use rayon::prelude::*;
+    use std::io::{Read, Write};
+    use std::path::Path;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
     use std::time::Instant;
 
+    const SAMPLE_GZ: &[u8] = include_bytes!("../test_data/sample.txt.gz");
+    const SAMPLE: &[u8] = include_bytes!("../test_data/sample.txt");
+    /* sample.txt stored by Info-ZIP with "zip -9" */
+    const SAMPLE_ZIP: &[u8] = include_bytes!("../test_data/sample.zip");
+    /* sample.txt.gz with FNAME and a UTF-8 FCOMMENT, as written by gzip
+     * implementations that support --comment (GNU gzip doesn't) */
+    const SAMPLE_COMMENT_GZ: &[u8] = include_bytes!("../test_data/sample_comment.txt.gz");
+
+    /* A file in the temporary directory, unique to the test, removed on drop */
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(test_name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!(
+                "streaming-libdeflate-{}-{}.gz",
+                std::process::id(),
+                test_name
+            )))
+        }
+    }
+
+    impl AsRef<Path> for TempFile {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn gunzip(mut compressed: &[u8]) -> Result<Vec<u8>, LibdeflateError> {
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        while input_stream.ensure_length(1) {
+            gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?;
+        }
+        Ok(output_stream.into_inner())
+    }
+
+    #[test]
+    fn adler32_vectors() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"a"), 0x00620062);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+
+        /* Long runs of 0xff stress the deferred modulo reduction */
+        let data = vec![0xff; 100_000];
+        let (mut s1, mut s2) = (1u32, 0u32);
+        for &byte in &data {
+            s1 = (s1 + byte as u32) % 65521;
+            s2 = (s2 + s1) % 65521;
+        }
+        assert_eq!(adler32(&data), (s2 << 16) | s1);
+
+        let mut hasher = Adler32::new();
+        for chunk in SAMPLE.chunks(1000) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), adler32(SAMPLE));
+
+        let (head, tail) = SAMPLE.split_at(12345);
+        let mut hasher = Adler32::new_with_initial(adler32(head));
+        hasher.update(tail);
+        assert_eq!(hasher.finalize(), adler32(SAMPLE));
+    }
+
+    #[test]
+    fn gunzip_to_vec_preallocates() {
+        let output = gunzip_to_vec(SAMPLE_GZ).unwrap();
+        assert_eq!(output, SAMPLE);
+        assert!(output.capacity() >= SAMPLE.len());
+
+        let mut reader = std::io::Cursor::new(SAMPLE_GZ);
+        reader.set_position(3);
+        assert_eq!(gzip_isize_hint_seek(&mut reader), Some(SAMPLE.len()));
+        assert_eq!(reader.position(), 3);
+
+        /* Implausible sizes are clamped, too short inputs give no hint */
+        let mut bogus = SAMPLE_GZ[..20].to_vec();
+        bogus.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(gzip_isize_hint(&bogus), Some(bogus.len() * 1032));
+        assert_eq!(gzip_isize_hint(&SAMPLE_GZ[..10]), None);
+    }
+
+    #[test]
+    fn gunzip_to_appends() {
+        let mut output = b"existing data".to_vec();
+        let data = [SAMPLE_GZ, SAMPLE_GZ].concat();
+        let result = gunzip_to(&data, &mut output).unwrap();
+
+        let expected = [&b"existing data"[..], SAMPLE, SAMPLE].concat();
+        assert_eq!(output, expected);
+        assert_eq!(result.written, 2 * SAMPLE.len());
+        assert_eq!(result.consumed, data.len());
+        assert_eq!(result.crc32, crc32fast::hash(&expected[13..]));
+        assert_eq!(
+            result.compression_ratio(result.consumed as u64),
+            result.written as f64 / data.len() as f64
+        );
+        assert_eq!(result.compression_ratio(0), 0.0);
+
+        /* A failed decompression leaves the existing data untouched */
+        let mut output = b"existing data".to_vec();
+        assert!(gunzip_to(&SAMPLE_GZ[..SAMPLE_GZ.len() - 4], &mut output).is_err());
+        assert_eq!(output, b"existing data");
+    }
+
+    #[test]
+    fn write_output_roundtrip() {
+        assert_eq!(gunzip(SAMPLE_GZ).unwrap(), SAMPLE);
+    }
+
+    /* Accepts 'room' bytes, then fails like a full disk */
+    struct FullWriter {
+        room: usize,
+        fail_flush: bool,
+    }
+
+    impl Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.room == 0 {
+                return Err(std::io::Error::other("no space left on device"));
+            }
+            let amount = buf.len().min(self.room);
+            self.room -= amount;
+            Ok(amount)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            if self.fail_flush {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "broken pipe",
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_output_errors() {
+        for (writer, kind) in [
+            (
+                FullWriter {
+                    room: 1000,
+                    fail_flush: false,
+                },
+                std::io::ErrorKind::Other,
+            ),
+            (
+                FullWriter {
+                    room: usize::MAX,
+                    fail_flush: true,
+                },
+                std::io::ErrorKind::BrokenPipe,
+            ),
+        ] {
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut output_stream = DeflateWriteOutput::new(writer, 1024 * 64);
+            let mut decompressor = libdeflate_alloc_decompressor();
+
+            match gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream) {
+                Err(LibdeflateError::WriteError(err)) => assert_eq!(err.kind(), kind),
+                result => panic!("unexpected result {:?}", result.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_input_short_reads() {
+        let mut compressed = SAMPLE_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(&mut buf[..1]), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        while input_stream.ensure_length(1) {
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        }
+        assert_eq!(output_stream.into_inner(), SAMPLE);
+
+        let telemetry = input_stream.take_telemetry();
+        assert_eq!(telemetry.total_bytes_read, SAMPLE_GZ.len() as u64);
+        assert!(telemetry.refill_count > 0);
+        assert_eq!(input_stream.take_telemetry().refill_count, 0);
+    }
+
+    #[test]
+    fn chunked_input_eof_mid_block() {
+        /* Random bytes don't compress, so the stored blocks span the whole member */
+        let mut data = Vec::new();
+        let mut seed = 1u32;
+        for _ in 0..100_000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            data.push((seed >> 16) as u8);
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&data).unwrap();
+        let member = encoder.finish().unwrap();
+
+        for cut in [member.len() / 2, member.len() - 10] {
+            let mut compressed = &member[..cut];
+            let reads_after_eof = std::cell::Cell::new(0);
+            let mut at_eof = false;
+            let mut input_stream = DeflateChunkedBufferInput::new(
+                |buf| {
+                    if at_eof {
+                        reads_after_eof.set(reads_after_eof.get() + 1);
+                    }
+                    let read = compressed.read(buf)?;
+                    at_eof = read == 0;
+                    Ok(read)
+                },
+                1024 * 16,
+            );
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            let mut decompressor = libdeflate_alloc_decompressor();
+
+            assert!(libdeflate_gzip_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream
+            )
+            .is_err());
+            assert!(!input_stream.ensure_length(1));
+            assert_eq!(reads_after_eof.get(), 0);
+        }
+    }
+
+    #[test]
+    fn truncated_gzip_stream() {
+        /* Cut in the header, in the compressed data and in the trailer */
+        for cut in [5, SAMPLE_GZ.len() / 2, SAMPLE_GZ.len() - 3] {
+            assert!(matches!(
+                gunzip(&SAMPLE_GZ[..cut]),
+                Err(LibdeflateError::Truncated { compressed_bytes_processed })
+                    if compressed_bytes_processed == cut as u64
+            ));
+        }
+
+        /* Corruption before the end of the input is still bad data */
+        let mut corrupt = SAMPLE_GZ.to_vec();
+        corrupt[GZIP_MIN_HEADER_SIZE] = 0xff;
+        assert!(matches!(
+            gunzip(&corrupt),
+            Err(LibdeflateError::BadData | LibdeflateError::InvalidHuffmanCode)
+        ));
+    }
+
+    #[test]
+    fn anon_mem_input_roundtrip() {
+        let data = [SAMPLE_GZ.to_vec(), stored_gzip_member()].concat();
+        let mut input_stream = unsafe { DeflateAnonMemInput::new(data.as_ptr(), data.len()) };
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        while !input_stream.is_at_eof() {
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        }
+        assert_eq!(output_stream.into_inner(), [SAMPLE, SAMPLE].concat());
+        assert!(!input_stream.ensure_length(1));
+
+        let cut = SAMPLE_GZ.len() / 2;
+        let mut input_stream = unsafe { DeflateAnonMemInput::new(SAMPLE_GZ.as_ptr(), cut) };
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        assert!(matches!(
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream),
+            Err(LibdeflateError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn chunked_input_eof() {
+        let mut data = &SAMPLE_GZ[..100];
+        let mut input_stream = DeflateChunkedBufferInput::new(|buf| data.read(buf), 1024);
+        /* Nothing was read yet */
+        assert!(!input_stream.is_at_eof());
+        let mut buf = [0; 60];
+        assert_eq!(input_stream.read(&mut buf), 60);
+        assert!(!input_stream.is_at_eof());
+        assert_eq!(input_stream.read(&mut buf), 40);
+        assert!(input_stream.is_at_eof());
+        assert!(!input_stream.ensure_length(1));
+    }
+
+    #[test]
+    fn raw_stream_ending_at_input_end() {
+        /* Without a gzip trailer the last symbols are decoded while the
+         * refills are already past the end of the input */
+        for size in [100, 1000, 4321, 70000] {
+            let mut compressor = flate2::Compress::new(flate2::Compression::default(), false);
+            let mut cdata = Vec::with_capacity(size + 1024);
+            compressor
+                .compress_vec(&SAMPLE[..size], &mut cdata, flate2::FlushCompress::Finish)
+                .unwrap();
+
+            let mut input_stream = unsafe { DeflateAnonMemInput::new(cdata.as_ptr(), cdata.len()) };
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            libdeflate_deflate_decompress(
+                &mut libdeflate_alloc_decompressor(),
+                &mut input_stream,
+                &mut output_stream,
+            )
+            .unwrap();
+            output_stream.final_flush().unwrap();
+            assert_eq!(input_stream.tell_stream_pos(), cdata.len());
+            assert_eq!(output_stream.into_inner(), &SAMPLE[..size]);
+
+            /* Truncated streams must still fail rather than decode zeros */
+            let mut input_stream =
+                unsafe { DeflateAnonMemInput::new(cdata.as_ptr(), cdata.len() - 1) };
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            assert!(libdeflate_deflate_decompress(
+                &mut libdeflate_alloc_decompressor(),
+                &mut input_stream,
+                &mut output_stream,
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn raw_stream_truncated_mid_word() {
+        /* Word refills near the cut see the zero padding after the valid
+         * bytes: none of those bits may complete the stream */
+        for (size, level) in [(100, 1), (1000, 9), (4321, 6), (70000, 6), (3000, 0)] {
+            let mut compressor = flate2::Compress::new(flate2::Compression::new(level), false);
+            let mut cdata = Vec::with_capacity(size + 1024);
+            compressor
+                .compress_vec(&SAMPLE[..size], &mut cdata, flate2::FlushCompress::Finish)
+                .unwrap();
+
+            for cut in cdata.len().saturating_sub(32)..cdata.len() {
+                let mut input_stream = unsafe { DeflateAnonMemInput::new(cdata.as_ptr(), cut) };
+                let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+                assert!(libdeflate_deflate_decompress(
+                    &mut libdeflate_alloc_decompressor(),
+                    &mut input_stream,
+                    &mut output_stream,
+                )
+                .is_err());
+
+                let mut compressed = &cdata[..cut];
+                let mut input_stream =
+                    DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 64);
+                let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+                assert!(libdeflate_deflate_decompress(
+                    &mut libdeflate_alloc_decompressor(),
+                    &mut input_stream,
+                    &mut output_stream,
+                )
+                .is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn raw_stream_corrupt_at_end() {
+        /* "ABC" and a match of 3 bytes at distance code 2 (3 bytes back),
+         * the last 2 bytes hold the distance code and the end of block */
+        let static_block = |distance_code| {
+            let mut stream = Vec::new();
+            let mut bit_pos = 0;
+            push_bits(&mut stream, &mut bit_pos, 1, 1);
+            push_bits(&mut stream, &mut bit_pos, 1, 2);
+            for &byte in b"ABC" {
+                push_codeword(&mut stream, &mut bit_pos, 0x30 + byte as u32, 8);
+            }
+            push_codeword(&mut stream, &mut bit_pos, 1, 7);
+            push_codeword(&mut stream, &mut bit_pos, distance_code, 5);
+            push_codeword(&mut stream, &mut bit_pos, 0, 7);
+            stream
+        };
+        let decompress = |data: &[u8]| {
+            let mut input_stream = unsafe { DeflateAnonMemInput::new(data.as_ptr(), data.len()) };
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            libdeflate_deflate_decompress(
+                &mut libdeflate_alloc_decompressor(),
+                &mut input_stream,
+                &mut output_stream,
+            )?;
+            output_stream.final_flush().unwrap();
+            Ok(output_stream.into_inner())
+        };
+
+        let valid = static_block(2);
+        assert_eq!(decompress(&valid).unwrap(), b"ABCABC");
+
+        /* A flipped bit makes it distance code 3, 4 bytes back: all its bits
+         * are in the input, even if the refills already read past its end */
+        let corrupt = static_block(3);
+        assert_eq!(corrupt.len(), valid.len());
+        assert_eq!(corrupt[..valid.len() - 2], valid[..valid.len() - 2]);
+        assert!(matches!(
+            decompress(&corrupt),
+            Err(LibdeflateError::BadData)
+        ));
+
+        /* Without its last byte the end of block is read from the padding */
+        assert!(matches!(
+            decompress(&valid[..valid.len() - 1]),
+            Err(LibdeflateError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn gzip_slice_decoder_reads() {
+        /* An empty member in the middle must not end the stream */
+        let empty = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default())
+            .finish()
+            .unwrap();
+        let data = [SAMPLE_GZ.to_vec(), empty, stored_gzip_member()].concat();
+        let expected = [SAMPLE, SAMPLE].concat();
+
+        for read_size in [1, 4096, expected.len() + 1] {
+            let mut decoder = GzipSliceDecoder::new(&data);
+            let mut decompressed = Vec::new();
+            let mut buf = vec![0; read_size];
+            loop {
+                let amount = decoder.read(&mut buf).unwrap();
+                if amount == 0 {
+                    break;
+                }
+                decompressed.extend_from_slice(&buf[..amount]);
+            }
+            assert_eq!(decompressed, expected);
+        }
+
+        let mut decompressed = Vec::new();
+        GzipSliceDecoder::new(&data)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, expected);
+
+        /* The data of the members before a corrupt one is still returned */
+        let mut corrupt = data.clone();
+        let trailer = data.len() - 8;
+        corrupt[trailer] ^= 1;
+        let mut decompressed = Vec::new();
+        let err = GzipSliceDecoder::new(&corrupt)
+            .read_to_end(&mut decompressed)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(decompressed, SAMPLE);
+    }
+
+    /* Fixed size buffer handed out in small windows */
+    struct ChunkSink {
+        buffer: Vec<u8>,
+        filled: usize,
+        window_size: usize,
+    }
+
+    impl DeflateSliceSink for ChunkSink {
+        fn window(&mut self) -> std::io::Result<&mut [u8]> {
+            let end = (self.filled + self.window_size).min(self.buffer.len());
+            Ok(&mut self.buffer[self.filled..end])
+        }
+
+        fn commit(&mut self, written: usize) -> std::io::Result<()> {
+            self.filled += written;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slice_sink_output() {
+        for window_size in [1, 1000, SAMPLE.len()] {
+            let mut sink = ChunkSink {
+                buffer: vec![0; SAMPLE.len()],
+                filled: 0,
+                window_size,
+            };
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut output_stream = DeflateChunkedBufferOutput::new_with_sink(&mut sink, 1024 * 64);
+            libdeflate_gzip_decompress(
+                &mut libdeflate_alloc_decompressor(),
+                &mut input_stream,
+                &mut output_stream,
+            )
+            .unwrap();
+            drop(output_stream);
+            assert_eq!(sink.filled, SAMPLE.len());
+            assert_eq!(sink.buffer, SAMPLE);
+        }
+
+        /* A full sink stops decompression */
+        let mut sink = ChunkSink {
+            buffer: vec![0; SAMPLE.len() - 1],
+            filled: 0,
+            window_size: 1000,
+        };
+        let mut compressed = SAMPLE_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new_with_sink(&mut sink, 1024 * 64);
+        assert!(libdeflate_gzip_decompress(
+            &mut libdeflate_alloc_decompressor(),
+            &mut input_stream,
+            &mut output_stream,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn chunked_input_read_exact() {
+        let mut data = SAMPLE;
+        let mut input_stream = DeflateChunkedBufferInput::new(|buf| data.read(buf), 1024 * 64);
+
+        /* Larger than the input buffer */
+        let mut head = vec![0; 100_000];
+        assert!(input_stream.read_exact(&mut head));
+        assert_eq!(head, &SAMPLE[..100_000]);
+
+        let mut rest = vec![0; SAMPLE.len() - 100_000];
+        assert!(input_stream.read_exact(&mut rest));
+        assert_eq!(rest, &SAMPLE[100_000..]);
+        assert!(input_stream.read_exact(&mut []));
+        assert!(!input_stream.read_exact(&mut [0]));
+    }
+
+    #[test]
+    fn zip_entry() {
+        /* Local file header fields, see APPNOTE.TXT 4.3.7 */
+        let field_u16 = |pos: usize| u16::from_le_bytes([SAMPLE_ZIP[pos], SAMPLE_ZIP[pos + 1]]);
+        let field_u32 =
+            |pos: usize| u32::from_le_bytes(SAMPLE_ZIP[pos..pos + 4].try_into().unwrap());
+        assert_eq!(field_u32(0), 0x04034b50);
+        assert_eq!(field_u16(8), ZIP_METHOD_DEFLATE);
+        let crc32 = field_u32(14);
+        let compressed_size = field_u32(18) as usize;
+        let uncompressed_size = field_u32(22);
+        let data_start = 30 + field_u16(26) as usize + field_u16(28) as usize;
+        let entry = &SAMPLE_ZIP[data_start..data_start + compressed_size];
+
+        assert_eq!(
+            decompress_zip_entry(entry, crc32, uncompressed_size).unwrap(),
+            SAMPLE
+        );
+
+        assert!(matches!(
+            decompress_zip_entry(entry, crc32 ^ 1, uncompressed_size),
+            Err(LibdeflateError::ChecksumMismatch { expected, actual })
+                if expected == crc32 ^ 1 && actual == crc32
+        ));
+        assert!(matches!(
+            decompress_zip_entry(entry, crc32, uncompressed_size + 1),
+            Err(LibdeflateError::LengthMismatch { expected, actual })
+                if expected == uncompressed_size + 1 && actual == uncompressed_size
+        ));
+        assert!(matches!(
+            decompress_zip_entry(entry, crc32, uncompressed_size - 1),
+            Err(LibdeflateError::InsufficientSpace)
+        ));
+        /* Data after the end of the stream doesn't belong to the entry */
+        let padded = [entry, &[0]].concat();
+        assert!(matches!(
+            decompress_zip_entry(&padded, crc32, uncompressed_size),
+            Err(LibdeflateError::BadData)
+        ));
+        assert!(
+            decompress_zip_entry(&entry[..compressed_size - 1], crc32, uncompressed_size).is_err()
+        );
+    }
+
+    /* Append 'len' bits of 'value', LSB first, to a DEFLATE bit stream */
+    fn push_bits(stream: &mut Vec<u8>, bit_pos: &mut usize, value: u32, len: usize) {
+        for i in 0..len {
+            if bit_pos.is_multiple_of(8) {
+                stream.push(0);
+            }
+            *stream.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (*bit_pos % 8);
+            *bit_pos += 1;
+        }
+    }
+
+    /* Huffman codewords are packed starting from their most significant bit */
+    fn push_codeword(stream: &mut Vec<u8>, bit_pos: &mut usize, codeword: u32, len: usize) {
+        for i in (0..len).rev() {
+            push_bits(stream, bit_pos, codeword >> i, 1);
+        }
+    }
+
+    #[test]
+    fn chunked_output_look_back_limit() {
+        /* The second copy is a match 2000 bytes back, found in the buffer
+         * before any flush but outside a 1024 bytes window */
+        let data = [&SAMPLE[..2000], &SAMPLE[..2000]].concat();
+        let mut compressor = flate2::Compress::new(flate2::Compression::default(), false);
+        let mut cdata = Vec::with_capacity(data.len() + 1024);
+        compressor
+            .compress_vec(&data, &mut cdata, flate2::FlushCompress::Finish)
+            .unwrap();
+
+        let decompress = |look_back| {
+            let mut input_stream = unsafe { DeflateAnonMemInput::new(cdata.as_ptr(), cdata.len()) };
+            let mut decompressed = Vec::new();
+            let mut output_stream = DeflateChunkedBufferOutput::new_with_look_back(
+                |data| {
+                    decompressed.extend_from_slice(data);
+                    Ok(())
+                },
+                1024 * 64,
+                look_back,
+            );
+            let result = libdeflate_deflate_decompress(
+                &mut libdeflate_alloc_decompressor(),
+                &mut input_stream,
+                &mut output_stream,
+            );
+            output_stream.final_flush().unwrap();
+            drop(output_stream);
+            result.map(|_| decompressed)
+        };
+
+        assert_eq!(decompress(4096).unwrap(), data);
+        assert!(matches!(decompress(1024), Err(LibdeflateError::BadData)));
+    }
+
+    #[test]
+    fn deflate64_long_match() {
+        /* A stored block, then a static block with a single match that is
+         * only valid in DEFLATE64: 1000 bytes from 40000 bytes back */
+        const HISTORY: usize = 40000;
+        const LENGTH: usize = 1000;
+        let history = &SAMPLE[..HISTORY];
+
+        let mut stream = Vec::new();
+        let mut bit_pos = 0;
+        push_bits(&mut stream, &mut bit_pos, 0, 3);
+        bit_pos = stream.len() * 8;
+        stream.extend_from_slice(&(HISTORY as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(HISTORY as u16)).to_le_bytes());
+        stream.extend_from_slice(history);
+        bit_pos += 32 + HISTORY * 8;
+        push_bits(&mut stream, &mut bit_pos, 1, 1);
+        push_bits(&mut stream, &mut bit_pos, 1, 2);
+        /* Length symbol 285, static code 11000101 */
+        push_codeword(&mut stream, &mut bit_pos, 0b11000101, 8);
+        push_bits(&mut stream, &mut bit_pos, (LENGTH - 3) as u32, 16);
+        /* Offset symbol 30, base 32769 */
+        push_codeword(&mut stream, &mut bit_pos, 30, 5);
+        push_bits(&mut stream, &mut bit_pos, (HISTORY - 32769) as u32, 14);
+        push_codeword(&mut stream, &mut bit_pos, 0, 7);
+
+        let expected = [history, &history[..LENGTH]].concat();
+
+        let decompress = |mut decompressor: LibdeflateDecompressor| {
+            let mut compressed = &stream[..];
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut decompressed = Vec::new();
+            let mut output_stream = DeflateChunkedBufferOutput::new_deflate64(
+                |data| {
+                    decompressed.extend_from_slice(data);
+                    Ok(())
+                },
+                DEFLATE64_MAX_WINDOW_SIZE + DEFLATE64_MAX_MATCH_LEN + 1,
+            );
+            let result = libdeflate_deflate_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+            );
+            output_stream.final_flush().unwrap();
+            drop(output_stream);
+            result.map(|_| decompressed)
+        };
+
+        assert_eq!(
+            decompress(libdeflate_alloc_deflate64_decompressor()).unwrap(),
+            expected
+        );
+        /* In plain DEFLATE, symbol 285 is a 258 byte match without extra bits */
+        assert_ne!(
+            decompress(libdeflate_alloc_decompressor()).ok(),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn precode_verification() {
+        let precode = |lens: &[u8]| {
+            let mut all = [0; DEFLATE_NUM_PRECODE_SYMS];
+            all[..lens.len()].copy_from_slice(lens);
+            all
+        };
+
+        /* The code of the RFC 1951 3.2.2 example (ABCDEFGH), all codewords
+         * of the same length, and lengths up to the 7 bits limit */
+        assert!(verify_precode(&precode(&[3, 3, 3, 3, 3, 2, 4, 4])));
+        assert!(verify_precode(&precode(&[1, 1])));
+        assert!(verify_precode(&precode(&[4; 16])));
+        assert!(verify_precode(&precode(&[1, 2, 3, 4, 5, 6, 7, 7])));
+        assert!(verify_precode(&precode(&[
+            5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 3, 3, 2
+        ])));
+
+        /* Empty, single codeword, incomplete, oversubscribed, too long */
+        assert!(!verify_precode(&precode(&[])));
+        assert!(!verify_precode(&precode(&[1])));
+        assert!(!verify_precode(&precode(&[2, 2, 2])));
+        assert!(!verify_precode(&precode(&[1, 1, 1])));
+        assert!(!verify_precode(&precode(&[1, 2, 3, 4, 5, 6, 7, 8, 8])));
+
+        /* Whatever is complete is accepted by the decode table builder */
+        let mut decompressor = libdeflate_alloc_decompressor();
+        let mut seed = 0x1234_5678u32;
+        let mut complete = 0;
+        for _ in 0..20000 {
+            let mut lens = [0; DEFLATE_NUM_PRECODE_SYMS];
+            for len in lens.iter_mut() {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                *len = ((seed >> 16) % 8) as u8 * ((seed >> 28) % 2) as u8;
+            }
+            decompressor.precode_lens = lens;
+            let built = build_precode_decode_table(&mut decompressor);
+            if verify_precode(&lens) {
+                complete += 1;
+                assert!(built);
+            } else if built {
+                assert!(lens.iter().filter(|&&len| len != 0).count() <= 1);
+            }
+        }
+        assert!(complete > 0);
+    }
+
+    #[test]
+    fn dynamic_block_lens() {
+        /* Random data over alphabets of different sizes, so that the codes
+         * range from a handful of symbols to all of them */
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut dynamic_blocks = 0;
+        for (size, alphabet) in [(100, 2), (5000, 4), (20000, 26), (70000, 256)] {
+            let data: Vec<u8> = (0..size)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    (seed % alphabet) as u8
+                })
+                .collect();
+
+            for level in [1, 6, 9] {
+                let mut compressor = flate2::Compress::new(flate2::Compression::new(level), false);
+                let mut cdata = Vec::with_capacity(data.len() + 1024);
+                compressor
+                    .compress_vec(&data, &mut cdata, flate2::FlushCompress::Finish)
+                    .unwrap();
+
+                let mut compressed = &cdata[..];
+                let mut input_stream =
+                    DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+                let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+                let mut decompressor = libdeflate_alloc_decompressor();
+                let blocks = libdeflate_deflate_decompress_with_lens(
+                    &mut decompressor,
+                    &mut input_stream,
+                    &mut output_stream,
+                )
+                .unwrap();
+                output_stream.final_flush().unwrap();
+                assert_eq!(output_stream.into_inner(), data);
+
+                dynamic_blocks += blocks.len();
+                for block in blocks {
+                    assert_eq!(block.litlen_lens.len(), block.num_litlen_syms);
+                    assert_eq!(block.offset_lens.len(), block.num_offset_syms);
+                    assert!(block
+                        .litlen_lens
+                        .iter()
+                        .all(|&len| len as usize <= DEFLATE_MAX_LITLEN_CODEWORD_LEN));
+                    assert!(block
+                        .offset_lens
+                        .iter()
+                        .all(|&len| len as usize <= DEFLATE_MAX_OFFSET_CODEWORD_LEN));
+                    /* zlib always emits a complete literal/length code */
+                    let kraft_sum: u32 = block
+                        .litlen_lens
+                        .iter()
+                        .filter(|&&len| len != 0)
+                        .map(|&len| 1 << (DEFLATE_MAX_LITLEN_CODEWORD_LEN - len as usize))
+                        .sum();
+                    assert_eq!(kraft_sum, 1 << DEFLATE_MAX_LITLEN_CODEWORD_LEN);
+                    assert_ne!(block.litlen_lens[256], 0);
+                }
+            }
+        }
+
+        /* Tiny inputs may be compressed with static blocks */
+        assert!(dynamic_blocks > 0);
+    }
+
+    #[test]
+    fn shrinking_dynamic_block_codes() {
+        /* Long matches need length symbols up to 285, then only literals and
+         * short matches: the second block has fewer litlen symbols, and the
+         * lengths left over from the first one must not be used */
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let first = b"0123456789".repeat(2000);
+        let second: Vec<u8> = (0..5000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                b'a' + (seed % 3) as u8
+            })
+            .collect();
+
+        let mut compressor = flate2::Compress::new(flate2::Compression::default(), false);
+        let mut cdata = Vec::with_capacity(first.len() + second.len() + 1024);
+        compressor
+            .compress_vec(&first, &mut cdata, flate2::FlushCompress::Full)
+            .unwrap();
+        compressor
+            .compress_vec(&second, &mut cdata, flate2::FlushCompress::Finish)
+            .unwrap();
+
+        let mut compressed = &cdata[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let blocks = libdeflate_deflate_decompress_with_lens(
+            &mut libdeflate_alloc_decompressor(),
+            &mut input_stream,
+            &mut output_stream,
+        )
+        .unwrap();
+        output_stream.final_flush().unwrap();
+        assert_eq!(output_stream.into_inner(), [first, second].concat());
+
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].num_litlen_syms, DEFLATE_NUM_LITLEN_SYMS - 2);
+        assert!(blocks[1].num_litlen_syms < blocks[0].num_litlen_syms);
+    }
+
+    /*
+     * Append a dynamic block writing "AAAAA", with a complete precode where
+     * the two symbols actually used, 1 and 18, have 7 bit codewords, the
+     * longest possible: symbols 0 and 2 - 6 take the lengths 1 - 6
+     */
+    fn push_max_length_precode_block(stream: &mut Vec<u8>, bit_pos: &mut usize, is_final: bool) {
+        let precode_lens: [u8; DEFLATE_NUM_PRECODE_SYMS] =
+            [1, 7, 2, 3, 4, 5, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7];
+        assert!(verify_precode(&precode_lens));
+        const PRESYM_1: u32 = 0b1111110;
+        const PRESYM_18: u32 = 0b1111111;
+
+        push_bits(stream, bit_pos, is_final as u32, 1);
+        push_bits(stream, bit_pos, 2, 2);
+        /* 257 litlen and 1 offset symbols, 18 precode lengths: up to symbol 1 */
+        push_bits(stream, bit_pos, 0, 5);
+        push_bits(stream, bit_pos, 0, 5);
+        push_bits(stream, bit_pos, 18 - 4, 4);
+        let permutation = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1];
+        for sym in permutation {
+            push_bits(stream, bit_pos, precode_lens[sym] as u32, 3);
+        }
+
+        /* Length 1 for 'A', the end of block and offset symbol 0, zeros
+         * in between in runs of at most 138 */
+        for run in [65, 0, 138, 52, 0, 0] {
+            if run == 0 {
+                push_codeword(stream, bit_pos, PRESYM_1, 7);
+            } else {
+                push_codeword(stream, bit_pos, PRESYM_18, 7);
+                push_bits(stream, bit_pos, run - 11, 7);
+            }
+        }
+
+        /* 'A' has the codeword 0, the end of block 1 */
+        push_bits(stream, bit_pos, 0b100000, 6);
+    }
+
+    /* Append a static block writing 'text', which must be all literals below 144 */
+    fn push_static_literals(
+        stream: &mut Vec<u8>,
+        bit_pos: &mut usize,
+        is_final: bool,
+        text: &[u8],
+    ) {
+        push_bits(stream, bit_pos, is_final as u32, 1);
+        push_bits(stream, bit_pos, 1, 2);
+        for &byte in text {
+            push_codeword(stream, bit_pos, 0x30 + byte as u32, 8);
+        }
+        push_codeword(stream, bit_pos, 0, 7);
+    }
+
+    #[test]
+    fn static_tables_after_dynamic_block() {
+        /* The last block must not decode with the dynamic tables */
+        let mut stream = Vec::new();
+        let mut bit_pos = 0;
+        push_static_literals(&mut stream, &mut bit_pos, false, b"BC");
+        push_max_length_precode_block(&mut stream, &mut bit_pos, false);
+        push_static_literals(&mut stream, &mut bit_pos, true, b"BC");
+        let static_only = {
+            let mut stream = Vec::new();
+            push_static_literals(&mut stream, &mut 0, true, b"static");
+            stream
+        };
+
+        let decompress = |decompressor: &mut LibdeflateDecompressor, data: &[u8]| {
+            let mut compressed = data;
+            let mut input_stream = DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 4096);
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            libdeflate_deflate_decompress(decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+            output_stream.final_flush().unwrap();
+            output_stream.into_inner()
+        };
+
+        /* Reusing the decompressor: the static tables of a stream may or may
+         * not survive the previous one */
+        let mut decompressor = libdeflate_alloc_decompressor();
+        for _ in 0..2 {
+            assert_eq!(decompress(&mut decompressor, &stream), b"BCAAAAABC");
+            assert_eq!(decompress(&mut decompressor, &static_only), b"static");
+            assert_eq!(decompress(&mut decompressor, &static_only), b"static");
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+            assert_eq!(decompress(&mut decompressor, &static_only), b"static");
+            decompressor.reset();
+        }
+    }
+
+    #[test]
+    fn max_length_precode() {
+        let mut stream = Vec::new();
+        let mut bit_pos = 0;
+        push_max_length_precode_block(&mut stream, &mut bit_pos, true);
+
+        let mut compressed = &stream[..];
+        let mut input_stream = DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 4096);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let blocks = libdeflate_deflate_decompress_with_lens(
+            &mut libdeflate_alloc_decompressor(),
+            &mut input_stream,
+            &mut output_stream,
+        )
+        .unwrap();
+        output_stream.final_flush().unwrap();
+        assert_eq!(output_stream.into_inner(), b"AAAAA");
+        assert_eq!(blocks[0].litlen_lens[b'A' as usize], 1);
+        assert_eq!(blocks[0].litlen_lens[256], 1);
+        assert_eq!(blocks[0].offset_lens, [1]);
+    }
+
+    #[test]
+    fn chunked_input_external_buffer() {
+        let mut compressed = SAMPLE_GZ;
+        let mut buffer = [0; 4096];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new_with_buffer(|buf| compressed.read(buf), &mut buffer);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+            .unwrap();
+        assert_eq!(output_stream.into_inner(), SAMPLE);
+    }
+
+    #[test]
+    fn ring_buffer_input_roundtrip() {
+        /* Odd sized reads, so that refills and stored blocks straddle the wrap */
+        for (ring_size, read_size) in [(64, 7), (4096, 1000), (1024 * 64, 1024 * 64)] {
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream = DeflateRingBufferInput::new(
+                |buf| {
+                    let len = buf.len().min(read_size);
+                    compressed.read(&mut buf[..len])
+                },
+                ring_size,
+            );
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            let mut decompressor = libdeflate_alloc_decompressor();
+
+            while input_stream.ensure_length(1) {
+                libdeflate_gzip_decompress(
+                    &mut decompressor,
+                    &mut input_stream,
+                    &mut output_stream,
+                )
+                .unwrap();
+            }
+            assert_eq!(output_stream.into_inner(), SAMPLE);
+        }
+    }
+
+    /* A gzip member made of stored blocks holding SAMPLE */
+    fn stored_gzip_member() -> Vec<u8> {
+        let mut member = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        let mut blocks = SAMPLE.chunks(u16::MAX as usize).peekable();
+        while let Some(block) = blocks.next() {
+            /* BFINAL, BTYPE = 00, then byte aligned LEN and NLEN */
+            member.push(blocks.peek().is_none() as u8);
+            member.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            member.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            member.extend_from_slice(block);
+        }
+        member.extend_from_slice(&crc32fast::hash(SAMPLE).to_le_bytes());
+        member.extend_from_slice(&(SAMPLE.len() as u32).to_le_bytes());
+        member
+    }
+
+    #[test]
+    fn bad_stored_block_values() {
+        /* NLEN of the second stored block, after the 10 bytes header and the
+         * first block (header byte, LEN, NLEN and 65535 bytes) */
+        let mut data = stored_gzip_member();
+        let block_start = 10 + 1 + 4 + u16::MAX as usize;
+        data[block_start + 3..block_start + 5].copy_from_slice(&1u16.to_le_bytes());
+
+        let mut compressed = &data[..];
+        let mut input_stream = DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 4096);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        let result = libdeflate_gzip_decompress(
+            &mut libdeflate_alloc_decompressor(),
+            &mut input_stream,
+            &mut output_stream,
+        );
+        assert!(matches!(
+            result,
+            Err(LibdeflateError::BadStoredBlock {
+                len: u16::MAX,
+                nlen: 1,
+                bit_position,
+            }) if bit_position == block_start as u64 * 8
+        ));
+    }
+
+    #[test]
+    fn stored_blocks_spanning_refills() {
+        const TRAILING: &[u8] = b"trailing data";
+        let mut data = stored_gzip_member();
+        data.extend_from_slice(TRAILING);
+
+        /* Input buffers much smaller than a stored block, so that each copy
+         * goes through many refills */
+        for (buf_size, read_size) in [(1000, 1000), (4096, 333), (1024 * 64, 1)] {
+            let mut compressed = &data[..];
+            let mut input_stream = DeflateChunkedBufferInput::new(
+                |buf| {
+                    let len = buf.len().min(read_size);
+                    compressed.read(&mut buf[..len])
+                },
+                buf_size,
+            );
+            let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+            let mut decompressor = libdeflate_alloc_decompressor();
+
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+            assert_eq!(output_stream.into_inner(), SAMPLE);
+
+            /* The input must be left exactly after the gzip trailer */
+            let mut trailing = [0; TRAILING.len()];
+            assert_eq!(input_stream.read(&mut trailing), TRAILING.len());
+            assert_eq!(&trailing, TRAILING);
+            assert!(!input_stream.ensure_length(1));
+        }
+    }
+
+    #[test]
+    fn gzip_member_consumed_bytes() {
+        let data = [SAMPLE_GZ, SAMPLE_GZ, b"trailing data"].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 16);
+        let mut output_stream = DeflateWriteOutput::new(std::io::sink(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        for member in 1..=2 {
+            let result = libdeflate_gzip_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+            )
+            .unwrap();
+            assert_eq!(result.compressed_bytes, SAMPLE_GZ.len() as u64);
+            assert_eq!(result.decompressed_bytes, SAMPLE.len() as u64);
+            assert_eq!(input_stream.tell_stream_pos(), member * SAMPLE_GZ.len());
+        }
+    }
+
+    #[test]
+    fn stored_block_insufficient_space() {
+        let mut compressed = &stored_gzip_member()[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        /* An output that can't flush has room for a single buffer */
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Err(()), 1024 * 16);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        assert!(matches!(
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream),
+            Err(LibdeflateError::InsufficientSpace)
+        ));
+
+        /* A stored block cut short is truncated data, not an output error */
+        let member = stored_gzip_member();
+        assert!(matches!(
+            gunzip(&member[..member.len() / 2]),
+            Err(LibdeflateError::Truncated { compressed_bytes_processed })
+                if compressed_bytes_processed == member.len() as u64 / 2
+        ));
+    }
+
+    #[test]
+    fn file_member_boundaries() {
+        let path = TempFile::new("file_member_boundaries");
+        let data = [gzip_with_header(b"first", b""), SAMPLE_GZ.to_vec()].concat();
+        std::fs::write(&path, data).unwrap();
+
+        /* Bytes of each member seen by the data callback */
+        let current = std::cell::Cell::new(0);
+        let mut members = Vec::new();
+        let stats = decompress_file_buffered_members(
+            &path,
+            |data| {
+                current.set(current.get() + data.len());
+                Ok(())
+            },
+            |header, result| {
+                members.push((header.name.clone(), result.written, current.take()));
+            },
+            1024 * 64,
+            TrailingPolicy::Reject,
+        )
+        .unwrap();
+
+        assert_eq!(stats.members_processed, 2);
+        assert_eq!(
+            members[0],
+            (Some("first".to_string()), SAMPLE.len(), SAMPLE.len())
+        );
+        assert_eq!(members[1].1, SAMPLE.len());
+        assert_eq!(members[1].2, SAMPLE.len());
+    }
+
+    #[test]
+    fn empty_inputs() {
+        let path = TempFile::new("empty_inputs");
+        let empty_member =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default())
+                .finish()
+                .unwrap();
+
+        /* No members at all isn't corrupt data */
+        std::fs::write(&path, []).unwrap();
+        let stats = decompress_file_buffered(&path, |_| Ok(()), 1024 * 64).unwrap();
+        assert_eq!(stats.members_processed, 0);
+        assert_eq!(stats.decompressed_bytes, 0);
+        assert_eq!(gunzip_to_vec(&[]).unwrap(), b"");
+
+        std::fs::write(&path, &empty_member).unwrap();
+        let stats = decompress_file_buffered(&path, |_| Ok(()), 1024 * 64).unwrap();
+        assert_eq!(stats.members_processed, 1);
+        assert_eq!(stats.decompressed_bytes, 0);
+        assert_eq!(gunzip_to_vec(&empty_member).unwrap(), b"");
+
+        /* A header alone, or part of it, is a truncated member */
+        for len in [1, 5, GZIP_MIN_HEADER_SIZE] {
+            std::fs::write(&path, &empty_member[..len]).unwrap();
+            assert!(matches!(
+                decompress_file_buffered(&path, |_| Ok(()), 1024 * 64),
+                Err(LibdeflateError::Truncated { compressed_bytes_processed })
+                    if compressed_bytes_processed == len as u64
+            ));
+            assert!(matches!(
+                gunzip_to_vec(&empty_member[..len]),
+                Err(LibdeflateError::Truncated { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn empty_gzip_members() {
+        let header = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        /* A final stored block with LEN 0, and a final static block with
+         * just the end of block symbol */
+        let stored = [&header[..], &[1, 0, 0, 0xff, 0xff], &[0; 8]].concat();
+        let fixed = [&header[..], &[3, 0], &[0; 8]].concat();
+
+        for member in [&stored, &fixed] {
+            let mut chunks = Vec::new();
+            let mut compressed = &member[..];
+            let mut input_stream = DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 4096);
+            let mut output_stream = DeflateChunkedBufferOutput::new(
+                |data| {
+                    chunks.push(data.len());
+                    Ok(())
+                },
+                DeflateChunkedBufferOutput::MIN_BUFFER_SIZE,
+            );
+            let result = libdeflate_gzip_decompress_with_header(
+                &mut libdeflate_alloc_decompressor(),
+                &mut input_stream,
+                &mut output_stream,
+                &mut GzipHeader::default(),
+            )
+            .unwrap();
+            assert_eq!(result.written, 0);
+            assert_eq!(result.crc32, 0);
+            assert_eq!(result.consumed, member.len());
+            assert_eq!(output_stream.flush_count(), 0);
+            drop(output_stream);
+            assert!(chunks.is_empty());
+
+            /* Between other members, the history of the previous one isn't
+             * output again */
+            let data = [SAMPLE_GZ, member, SAMPLE_GZ].concat();
+            assert_eq!(gunzip_to_vec(&data).unwrap(), [SAMPLE, SAMPLE].concat());
+            let mut output = Vec::new();
+            let mut compressed = &data[..];
+            let mut input_stream = DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 4096);
+            let mut output_stream = DeflateChunkedBufferOutput::new(
+                |data| {
+                    output.extend_from_slice(data);
+                    Ok(())
+                },
+                1024 * 64,
+            );
+            let mut decompressor = libdeflate_alloc_decompressor();
+            let mut members = Vec::new();
+            while !input_stream.is_at_eof() {
+                members.push(
+                    libdeflate_gzip_decompress(
+                        &mut decompressor,
+                        &mut input_stream,
+                        &mut output_stream,
+                    )
+                    .unwrap()
+                    .decompressed_bytes,
+                );
+                input_stream.ensure_length(1);
+            }
+            drop(output_stream);
+            assert_eq!(members, [SAMPLE.len() as u64, 0, SAMPLE.len() as u64]);
+            assert_eq!(output, [SAMPLE, SAMPLE].concat());
+        }
+    }
+
+    #[test]
+    fn file_trailing_data() {
+        let path = TempFile::new("file_trailing_data");
+        let decompress = |trailing| {
+            decompress_file_buffered_members(&path, |_| Ok(()), |_, _| {}, 1024 * 64, trailing)
+        };
+
+        let padded = [SAMPLE_GZ, &[0; 1000]].concat();
+        std::fs::write(&path, &padded).unwrap();
+
+        let stats = decompress(TrailingPolicy::Ignore).unwrap();
+        assert_eq!(stats.members_processed, 1);
+        assert_eq!(stats.decompressed_bytes, SAMPLE.len() as u64);
+        assert_eq!(stats.trailing_bytes, 1000);
+        assert!(stats.trailing_data.is_none());
+
+        let stats = decompress(TrailingPolicy::Return).unwrap();
+        assert_eq!(stats.trailing_data.unwrap(), vec![0; 1000]);
+
+        assert!(decompress(TrailingPolicy::Reject).is_err());
+
+        /* A single trailing byte can't even be peeked as a gzip magic */
+        std::fs::write(&path, [SAMPLE_GZ, SAMPLE_GZ, b"x"].concat()).unwrap();
+        let stats = decompress(TrailingPolicy::Return).unwrap();
+        assert_eq!(stats.members_processed, 2);
+        assert_eq!(stats.trailing_data.unwrap(), b"x");
+    }
+
+    #[test]
+    fn file_recover_members() {
+        let path = TempFile::new("file_recover_members");
+        let mut bad_crc = SAMPLE_GZ.to_vec();
+        let crc_pos = bad_crc.len() - 8;
+        bad_crc[crc_pos] ^= 1;
+        let data = [SAMPLE_GZ, &bad_crc, b"garbage", SAMPLE_GZ].concat();
+        std::fs::write(&path, data).unwrap();
+
+        let (stats, outcomes) =
+            decompress_file_buffered_recover(&path, |_| Ok(()), 1024 * 64).unwrap();
+        assert!(decompress_file_buffered(&path, |_| Ok(()), 1024 * 64).is_err());
+
+        assert_eq!(stats.members_processed, 2);
+        assert_eq!(stats.decompressed_bytes, 3 * SAMPLE.len() as u64);
+        /* The garbage after the corrupt member is skipped with it */
+        let offsets: Vec<_> = outcomes.iter().map(|outcome| outcome.offset).collect();
+        let member_len = SAMPLE_GZ.len() as u64;
+        assert_eq!(
+            offsets,
+            [0, member_len, 2 * member_len + b"garbage".len() as u64]
+        );
+        assert_eq!(*outcomes[0].result.as_ref().unwrap(), SAMPLE.len());
+        assert!(matches!(
+            outcomes[1].result,
+            Err(LibdeflateError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(*outcomes[2].result.as_ref().unwrap(), SAMPLE.len());
+
+        /* Garbage after a valid member is reported as a damaged member */
+        std::fs::write(&path, [SAMPLE_GZ, b"garbage", SAMPLE_GZ].concat()).unwrap();
+        let (stats, outcomes) =
+            decompress_file_buffered_recover(&path, |_| Ok(()), 1024 * 64).unwrap();
+        assert_eq!(stats.members_processed, 2);
+        assert_eq!(outcomes[1].offset, member_len);
+        assert!(matches!(outcomes[1].result, Err(LibdeflateError::BadMagic)));
+        assert_eq!(outcomes.len(), 3);
+    }
+
+    #[test]
+    fn file_decompress_stats() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/sample.txt.gz");
+        let mut output = Vec::new();
+        let stats = decompress_file_buffered(
+            path,
+            |data| {
+                output.extend_from_slice(data);
+                Ok(())
+            },
+            1024 * 64,
+        )
+        .unwrap();
+
+        assert_eq!(output, SAMPLE);
+        assert_eq!(stats.members_processed, 1);
+        assert_eq!(stats.compressed_bytes, SAMPLE_GZ.len() as u64);
+        assert_eq!(stats.decompressed_bytes, SAMPLE.len() as u64);
+        assert!(stats.compression_ratio() > 1.0);
+    }
+
+    #[test]
+    fn decompress_from_iter() {
+        let data = [SAMPLE_GZ, SAMPLE_GZ, b"trailing"].concat();
+        let mut output = Vec::new();
+        decompress_gzip_from_iter(
+            data.iter().copied(),
+            |chunk| {
+                output.extend_from_slice(chunk);
+                Ok(())
+            },
+            1024 * 64,
+        )
+        .unwrap();
+        assert_eq!(output, [SAMPLE, SAMPLE].concat());
+
+        let truncated = SAMPLE_GZ[..SAMPLE_GZ.len() - 100].iter().copied();
+        assert!(decompress_gzip_from_iter(truncated, |_| Ok(()), 1024 * 64).is_err());
+    }
+
+    #[test]
+    fn file_decompress_lines() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/sample.txt.gz");
+        let mut output = Vec::new();
+        let lines = decompress_gzip_lines(path, |line| {
+            assert!(line.ends_with(b"\n") || output.len() + line.len() == SAMPLE.len());
+            output.extend_from_slice(line);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(output, SAMPLE);
+        assert_eq!(lines, SAMPLE.split_inclusive(|&b| b == b'\n').count());
+
+        /* Lines spanning members, "\r\n" endings and no final newline */
+        let gzip = |data: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let path = TempFile::new("file_decompress_lines");
+        std::fs::write(
+            &path,
+            [gzip(b"first\r"), gzip(b"\nsec"), gzip(b"ond\nlast")].concat(),
+        )
+        .unwrap();
+        let mut lines = Vec::new();
+        let count = decompress_gzip_lines(&path, |line| {
+            lines.push(line.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(lines, [&b"first\r\n"[..], b"second\n", b"last"]);
+    }
+
+    #[test]
+    fn line_output_max_line_len() {
+        let gzip = |data: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        /* The long line spans the two members */
+        let data = [gzip(b"abcdef"), gzip(b"ghij\nxyzw\nshort")].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut lines = Vec::new();
+        let mut output_stream = DeflateLineOutput::new(|line| {
+            lines.push(line.to_vec());
+            Ok(())
+        });
+        output_stream.set_max_line_len(4);
+        let mut decompressor = libdeflate_alloc_decompressor();
+        while input_stream.ensure_length(1) {
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        }
+        assert_eq!(output_stream.finish().unwrap(), 7);
+        assert_eq!(
+            lines,
+            [
+                &b"abcd"[..],
+                b"efgh",
+                b"ij\n",
+                b"xyzw",
+                b"\n",
+                b"shor",
+                b"t"
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn gzip_header_serde_roundtrip() {
+        let header = GzipHeader {
+            mtime: 1234567890,
+            xfl: 2,
+            os: 3,
+            extra: Some(vec![b'B', b'C', 2, 0, 0xff, 0]),
+            name: Some("sample.txt".to_string()),
+            comment: None,
+            is_text: true,
+        };
+        let json = serde_json::to_string(&header).unwrap();
+        let decoded: GzipHeader = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.mtime, header.mtime);
+        assert_eq!(decoded.xfl, header.xfl);
+        assert_eq!(decoded.os, header.os);
+        assert_eq!(decoded.extra, header.extra);
+        assert_eq!(decoded.name, header.name);
+        assert_eq!(decoded.comment, header.comment);
+        assert_eq!(decoded.is_text, header.is_text);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_output_chunks() {
+        use crate::streams::deflate_bytes_output::DeflateBytesOutput;
+
+        let data = [SAMPLE_GZ, SAMPLE_GZ].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut chunks = Vec::new();
+        let mut output_stream = DeflateBytesOutput::new(
+            |chunk| {
+                chunks.push(chunk);
+                Ok(())
+            },
+            1024 * 64,
+        );
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        for _ in 0..2 {
+            let result = libdeflate_gzip_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+            )
+            .unwrap();
+            assert_eq!(result.decompressed_bytes, SAMPLE.len() as u64);
+        }
+        drop(output_stream);
+
+        /* The chunks outlive the output and don't include the window */
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+        assert_eq!(chunks.concat(), [SAMPLE, SAMPLE].concat());
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_decompress() {
+        use crate::ffi::*;
+        use std::ptr::null_mut;
+
+        let d = libdeflate_alloc_decompressor();
+        let mut out = vec![0u8; SAMPLE.len() + 1];
+        let mut actual = 0;
+        let gzip = |data: &[u8], out: &mut [u8], actual: *mut usize| unsafe {
+            libdeflate_gzip_decompress(
+                d,
+                data.as_ptr().cast(),
+                data.len(),
+                out.as_mut_ptr().cast(),
+                out.len(),
+                actual,
+            )
+        };
+
+        assert_eq!(gzip(SAMPLE_GZ, &mut out, &mut actual), LIBDEFLATE_SUCCESS);
+        assert_eq!(&out[..actual], SAMPLE);
+        assert_eq!(
+            gzip(SAMPLE_GZ, &mut out[..SAMPLE.len()], null_mut()),
+            LIBDEFLATE_SUCCESS
+        );
+        assert_eq!(
+            gzip(SAMPLE_GZ, &mut out, null_mut()),
+            LIBDEFLATE_SHORT_OUTPUT
+        );
+        assert_eq!(
+            gzip(SAMPLE_GZ, &mut out[..SAMPLE.len() - 1], &mut actual),
+            LIBDEFLATE_INSUFFICIENT_SPACE
+        );
+        assert_eq!(
+            gzip(&SAMPLE_GZ[1..], &mut out, &mut actual),
+            LIBDEFLATE_BAD_DATA
+        );
+
+        /* The raw DEFLATE stream of the member, the header has no optional
+         * fields */
+        let raw = &SAMPLE_GZ[GZIP_MIN_HEADER_SIZE..SAMPLE_GZ.len() - 8];
+        let result = unsafe {
+            libdeflate_deflate_decompress(
+                d,
+                raw.as_ptr().cast(),
+                raw.len(),
+                out.as_mut_ptr().cast(),
+                out.len(),
+                &mut actual,
+            )
+        };
+        assert_eq!(result, LIBDEFLATE_SUCCESS);
+        assert_eq!(&out[..actual], SAMPLE);
+
+        unsafe {
+            libdeflate_free_decompressor(d);
+            libdeflate_free_decompressor(null_mut());
+        }
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn file_decompress_hashed() {
+        use crate::{decompress_file_buffered_hashed, decompress_file_buffered_per_member_hash};
+        use sha2::{Digest, Sha256};
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/sample.txt.gz");
+        let (stats, digest) =
+            decompress_file_buffered_hashed::<Sha256>(path, |_| Ok(()), 1024 * 64).unwrap();
+        assert_eq!(stats.decompressed_bytes, SAMPLE.len() as u64);
+        assert_eq!(digest, Sha256::digest(SAMPLE).to_vec());
+
+        /* Two concatenated members */
+        let path = TempFile::new("file_decompress_hashed");
+        std::fs::write(&path, [SAMPLE_GZ, SAMPLE_GZ].concat()).unwrap();
+        let (stats, digests) =
+            decompress_file_buffered_per_member_hash::<Sha256>(&path, |_| Ok(()), 1024 * 64)
+                .unwrap();
+        assert_eq!(stats.members_processed, 2);
+        for (_, digest) in digests {
+            assert_eq!(digest, Sha256::digest(SAMPLE).to_vec());
+        }
+    }
+
+    #[test]
+    fn tee_output_both_sinks() {
+        let mut first = Vec::new();
+        let mut second_len = 0;
+        let mut second_crc = crc32fast::Hasher::new();
+        {
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut output_stream = DeflateChunkedBufferOutput::new_tee(
+                |data| {
+                    first.extend_from_slice(data);
+                    Ok(())
+                },
+                |data| {
+                    second_len += data.len();
+                    second_crc.update(data);
+                    Ok(())
+                },
+                1024 * 64,
+            );
+            let mut decompressor = libdeflate_alloc_decompressor();
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        }
+        assert_eq!(first, SAMPLE);
+        assert_eq!(second_len, SAMPLE.len());
+        assert_eq!(second_crc.finalize(), crc32fast::hash(SAMPLE));
+    }
+
+    #[test]
+    fn output_quota_exceeded() {
+        for (max_output, expect_ok) in [
+            (1000, false),
+            (SAMPLE.len() - 1, false),
+            (SAMPLE.len(), true),
+        ] {
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+            output_stream.set_max_output(max_output);
+            let mut decompressor = libdeflate_alloc_decompressor();
+
+            let result = libdeflate_gzip_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+            );
+            if expect_ok {
+                assert_eq!(result.unwrap().decompressed_bytes, SAMPLE.len() as u64);
+            } else {
+                assert!(matches!(result, Err(LibdeflateError::InsufficientSpace)));
+                assert!(output_stream.is_quota_exceeded());
+            }
+        }
+    }
+
+    #[test]
+    fn output_quota_lowered_and_reset() {
+        let data = [SAMPLE_GZ, SAMPLE_GZ].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+            .unwrap();
+
+        /* Below what has already been written */
+        output_stream.set_max_output(SAMPLE.len() / 2);
+        let result =
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream);
+        assert!(matches!(result, Err(LibdeflateError::InsufficientSpace)));
+        assert!(output_stream.is_quota_exceeded());
+
+        output_stream.reset_counters();
+        output_stream.set_max_output(SAMPLE.len());
+        assert!(!output_stream.is_quota_exceeded());
+        let mut compressed = SAMPLE_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let result = libdeflate_gzip_decompress_with_header(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+            &mut GzipHeader::default(),
+        )
+        .unwrap();
+        assert_eq!(result.written, SAMPLE.len());
+        assert_eq!(result.crc32, crc32fast::hash(SAMPLE));
+    }
+
+    #[test]
+    fn block_callback_positions() {
+        let member = stored_gzip_member();
+        let mut compressed = &member[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        let mut cursors = Vec::new();
+        libdeflate_gzip_decompress_on_block(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+            |cursor| cursors.push(*cursor),
+        )
+        .unwrap();
+
+        /* Each stored block is a header byte, LEN, NLEN and the data */
+        let blocks = SAMPLE.len().div_ceil(u16::MAX as usize);
+        assert_eq!(cursors.len(), blocks);
+        for (i, cursor) in cursors.iter().enumerate() {
+            assert_eq!(cursor.block_type, 0);
+            assert_eq!(cursor.is_final, i == blocks - 1);
+            let byte_position = GZIP_MIN_HEADER_SIZE + i * (u16::MAX as usize + 5);
+            assert_eq!(cursor.bit_position, byte_position as u64 * 8);
+            assert_eq!(cursor.uncompressed_offset, Some(i as u64 * u16::MAX as u64));
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn block_stats() {
+        let mut compressed = SAMPLE_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+        let mut block_types = [0; 3];
+        let result = libdeflate_gzip_decompress_on_block(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+            |cursor| block_types[cursor.block_type as usize] += 1,
+        )
+        .unwrap();
+        assert_eq!(result.uncompressed_blocks, block_types[0]);
+        assert_eq!(result.static_blocks, block_types[1]);
+        assert_eq!(result.dynamic_blocks, block_types[2]);
+        assert!(result.total_symbols > 0 && result.total_symbols < SAMPLE.len() as u64);
+
+        /* Stored blocks have no symbols, the totals cover all the members */
+        let data = [SAMPLE_GZ.to_vec(), stored_gzip_member()].concat();
+        let total = gunzip_to(&data, &mut Vec::new()).unwrap();
+        let stored_blocks = SAMPLE.len().div_ceil(u16::MAX as usize) as u32;
+        assert_eq!(total.uncompressed_blocks, block_types[0] + stored_blocks);
+        assert_eq!(total.static_blocks, result.static_blocks);
+        assert_eq!(total.dynamic_blocks, result.dynamic_blocks);
+        assert_eq!(total.total_symbols, result.total_symbols);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/sample.txt.gz");
+        let stats = decompress_file_buffered(path, |_| Ok(()), 1024 * 64).unwrap();
+        assert_eq!(stats.dynamic_blocks, result.dynamic_blocks);
+        assert_eq!(stats.total_symbols, result.total_symbols);
+    }
+
+    #[cfg(feature = "callbacks")]
+    #[test]
+    fn decompress_callbacks() {
+        let blocks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let errors = Arc::new(AtomicUsize::new(0));
+        let mut decompressor = libdeflate_alloc_decompressor();
+        let blocks_ref = blocks.clone();
+        decompressor.set_on_block_done(move |bit_position, block_type, size| {
+            blocks_ref
+                .lock()
+                .unwrap()
+                .push((bit_position, block_type, size))
+        });
+        let errors_ref = errors.clone();
+        decompressor.set_on_error(move |_| {
+            errors_ref.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let mut compressed = SAMPLE_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+            .unwrap();
+
+        let blocks = std::mem::take(&mut *blocks.lock().unwrap());
+        assert!(!blocks.is_empty());
+        assert!(blocks.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(blocks.iter().all(|&(_, block_type, _)| block_type < 3));
+        let total: usize = blocks.iter().map(|&(_, _, size)| size.unwrap()).sum();
+        assert_eq!(total, SAMPLE.len());
+        assert_eq!(errors.load(Ordering::Relaxed), 0);
+
+        /* A single report for an error, even when it crosses the formats */
+        let mut data = SAMPLE_GZ.to_vec();
+        let len = data.len();
+        data[len / 2] ^= 0xff;
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        let result =
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream);
+        assert!(result.is_err());
+        assert_eq!(errors.load(Ordering::Relaxed), 1);
+
+        /* Errors of the BGZF wrapper, a CRC mismatch here, are reported too */
+        let mut data = bgzf_block(b"bgzf data");
+        let len = data.len();
+        data[len - 8] ^= 1;
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        let result = crate::decompress_bgzf::decompress_bgzf_member(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+        );
+        assert!(matches!(
+            result,
+            Err(LibdeflateError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(errors.load(Ordering::Relaxed), 2);
+
+        /* And those of the debugging entry points */
+        let mut compressed: &[u8] = &[0xff];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        let result = libdeflate_deflate_decompress_with_lens(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+        );
+        assert!(result.is_err());
+        assert_eq!(errors.load(Ordering::Relaxed), 3);
+
+        /* The callbacks don't stop a decompressor from being shared */
+        fn assert_sync<T: Sync>(_: &T) {}
+        assert_sync(&decompressor);
+    }
+
+    #[test]
+    fn line_aligned_chunks() {
+        /* A line longer than the buffer has to be split */
+        let mut text = SAMPLE.to_vec();
+        text.extend_from_slice(&[b'x'; 20000]);
+        text.extend_from_slice(b"\nlast line without newline");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&text).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut chunks = Vec::new();
+        let mut output_stream = DeflateChunkedBufferOutput::new(
+            |chunk| {
+                chunks.push(chunk.to_vec());
+                Ok(())
+            },
+            DeflateChunkedBufferOutput::MAX_LOOK_BACK + 10000,
+        );
+        output_stream.set_line_aligned(true);
+        let mut decompressor = libdeflate_alloc_decompressor();
+        let result =
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        drop(output_stream);
+
+        assert_eq!(result.decompressed_bytes, text.len() as u64);
+        assert_eq!(chunks.concat(), text);
+        let split = chunks
+            .iter()
+            .filter(|chunk| !chunk.ends_with(b"\n"))
+            .count();
+        /* The long line is cut once, and the member ends without a newline */
+        assert_eq!(split, 2);
+        assert!(chunks.len() > 20);
+    }
+
+    #[test]
+    fn min_flush_bytes() {
+        let decompress = |min_bytes| {
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut chunks = Vec::new();
+            let mut output_stream = DeflateChunkedBufferOutput::new(
+                |chunk| {
+                    chunks.push(chunk.to_vec());
+                    Ok(())
+                },
+                DeflateChunkedBufferOutput::MAX_LOOK_BACK + 2000,
+            );
+            output_stream.set_min_flush_bytes(min_bytes);
+            let mut decompressor = libdeflate_alloc_decompressor();
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+            drop(output_stream);
+            chunks
+        };
+
+        let chunks = decompress(0);
+        assert!(chunks.iter().any(|chunk| chunk.len() < 4000));
+        assert_eq!(chunks.concat(), SAMPLE);
+
+        let chunks = decompress(4000);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.len() >= 4000));
+        assert_eq!(chunks.concat(), SAMPLE);
+
+        /* Once the pending data covers the window, it can fill the buffer */
+        let chunks = decompress(1 << 20);
+        assert!(chunks.len() > 1);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.len() > DeflateChunkedBufferOutput::MAX_LOOK_BACK));
+        assert_eq!(chunks.concat(), SAMPLE);
+    }
+
+    #[test]
+    fn caller_provided_output_buffer() {
+        let mut buffer = vec![0; DeflateChunkedBufferOutput::MIN_BUFFER_SIZE].into_boxed_slice();
+        let buffer_ptr = buffer.as_ptr();
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        /* The buffer is smaller than the sample, so it is flushed and reused */
+        for _ in 0..2 {
+            let mut compressed = SAMPLE_GZ;
+            let mut input_stream =
+                DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+            let mut output = Vec::new();
+            let mut output_stream = DeflateChunkedBufferOutput::with_buffer(buffer, |data| {
+                output.extend_from_slice(data);
+                Ok(())
+            });
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+            buffer = output_stream.into_buffer().unwrap();
+            assert_eq!(output, SAMPLE);
+        }
+        assert_eq!(buffer.as_ptr(), buffer_ptr);
+
+        let mut compressed = SAMPLE_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output = Vec::new();
+        let mut output_stream =
+            DeflateChunkedBufferOutput::with_borrowed_buffer(&mut buffer, |data| {
+                output.extend_from_slice(data);
+                Ok(())
+            });
+        libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+            .unwrap();
+        assert!(output_stream.into_buffer().is_none());
+        assert_eq!(output, SAMPLE);
+    }
+
+    #[test]
+    fn ring_output_partial_drains() {
+        /* The stored member exercises the direct buffer writes */
+        let data = [SAMPLE_GZ.to_vec(), stored_gzip_member()].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output = Vec::new();
+        let mut output_stream = DeflateRingOutput::new(
+            |pending| {
+                let amount = pending.len().min(1000);
+                output.extend_from_slice(&pending[..amount]);
+                Ok(amount)
+            },
+            1024 * 64,
+        );
+        let mut decompressor = libdeflate_alloc_decompressor();
+        for _ in 0..2 {
+            let result = libdeflate_gzip_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+            )
+            .unwrap();
+            assert_eq!(result.decompressed_bytes, SAMPLE.len() as u64);
+        }
+        drop(output_stream);
+        assert_eq!(output, [SAMPLE, SAMPLE].concat());
+
+        /* A consumer that stops taking data stops decompression */
+        let mut compressed = SAMPLE_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut drained = 0;
+        let mut output_stream = DeflateRingOutput::new(
+            |pending| {
+                let amount = pending.len().min(100_000 - drained);
+                drained += amount;
+                Ok(amount)
+            },
+            1024 * 64,
+        );
+        assert!(libdeflate_gzip_decompress(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn verify_output() {
+        /* Longer than the buffer, so that the window is moved and the
+         * members are hashed across evictions */
+        let mut text = Vec::new();
+        while text.len() < 2 * DeflateVerifyOutput::BUFFER_SIZE {
+            text.extend_from_slice(SAMPLE);
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&text).unwrap();
+        let data = [encoder.finish().unwrap(), stored_gzip_member()].concat();
+
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateVerifyOutput::new();
+        let mut decompressor = libdeflate_alloc_decompressor();
+        for expected in [&text[..], SAMPLE] {
+            let result = libdeflate_gzip_decompress_with_header(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+                &mut GzipHeader::default(),
+            )
+            .unwrap();
+            assert_eq!(result.written, expected.len());
+            assert_eq!(result.crc32, crc32fast::hash(expected));
+        }
+
+        /* The trailer is still checked */
+        let mut corrupt = SAMPLE_GZ.to_vec();
+        let len = corrupt.len();
+        corrupt[len - 5] ^= 1;
+        let mut compressed = &corrupt[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        assert!(matches!(
+            libdeflate_gzip_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut DeflateVerifyOutput::new()
+            ),
+            Err(LibdeflateError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn tee_output_roundtrip() {
+        /* The stored member exercises the direct buffer writes */
+        let data = [SAMPLE_GZ.to_vec(), stored_gzip_member()].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut chunked = Vec::new();
+        let mut output_stream = DeflateTeeOutput::new3(
+            DeflateWriteOutput::new(Vec::new(), 1024 * 64),
+            DeflateChunkedBufferOutput::new(
+                |data| {
+                    chunked.extend_from_slice(data);
+                    Ok(())
+                },
+                1024 * 64,
+            ),
+            DeflateWriteOutput::new(Vec::new(), 1024 * 100),
+        );
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        while input_stream.ensure_length(1) {
+            let result = libdeflate_gzip_decompress(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+            )
+            .unwrap();
+            assert_eq!(result.decompressed_bytes, SAMPLE.len() as u64);
+        }
+        let (first, rest) = output_stream.into_inner();
+        let (_, third) = rest.into_inner();
+        let expected = [SAMPLE, SAMPLE].concat();
+        assert_eq!(first.into_inner(), expected);
+        assert_eq!(third.into_inner(), expected);
+        assert_eq!(chunked, expected);
+
+        /* A failing second output fails the member */
+        let member = stored_gzip_member();
+        let mut compressed = &member[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateTeeOutput::new(
+            DeflateWriteOutput::new(Vec::new(), 1024 * 64),
+            DeflateChunkedBufferOutput::new(|_| Err(()), 1024 * 64),
+        );
+        assert!(libdeflate_gzip_decompress(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn chunked_output_counters() {
+        let data = [SAMPLE_GZ, SAMPLE_GZ].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let flushes = std::cell::Cell::new(0);
+        let mut output_stream = DeflateChunkedBufferOutput::new(
+            |_| {
+                flushes.set(flushes.get() + 1);
+                Ok(())
+            },
+            1024 * 64,
+        );
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        while input_stream.ensure_length(1) {
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        }
+        assert_eq!(output_stream.bytes_written_so_far(), 2 * SAMPLE.len());
+        assert_eq!(output_stream.flush_count(), flushes.get());
+
+        output_stream.reset_counters();
+        assert_eq!(output_stream.bytes_written_so_far(), 0);
+        assert_eq!(output_stream.flush_count(), 0);
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn chunked_output_digest() {
+        use sha2::{Digest, Sha256};
+
+        let data = [SAMPLE_GZ, SAMPLE_GZ].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateChunkedBufferOutput::new(|_| Ok(()), 1024 * 64);
+        output_stream.set_digest(Box::new(Sha256::new()));
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        /* The digest is reset after each member */
+        for _ in 0..2 {
+            let result = libdeflate_gzip_decompress_with_header(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+                &mut GzipHeader::default(),
+            )
+            .unwrap();
+            assert_eq!(result.digest.unwrap()[..], Sha256::digest(SAMPLE)[..]);
+        }
+    }
+
+    #[test]
+    fn chunked_input_read_error() {
+        let mut compressed = &SAMPLE_GZ[..SAMPLE_GZ.len() / 2];
+        let failures = std::cell::Cell::new(0);
+        let mut input_stream = DeflateChunkedBufferInput::new(
+            |buf| match compressed.read(buf)? {
+                0 => {
+                    failures.set(failures.get() + 1);
+                    Err(std::io::Error::other("connection reset"))
+                }
+                count => Ok(count),
+            },
+            1024 * 4,
+        );
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        assert!(matches!(
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream),
+            Err(LibdeflateError::ReadError(_))
+        ));
+
+        /* Taking the error doesn't make the input read again */
+        assert!(!input_stream.ensure_length(1024 * 4));
+        assert!(input_stream.take_read_error().is_none());
+        assert_eq!(failures.get(), 1);
+    }
+
+    #[cfg(feature = "double-buffer")]
+    #[test]
+    fn double_buffered_input() {
+        /* Short reads from the source, many buffer swaps */
+        struct Trickle(std::io::Cursor<Vec<u8>>);
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(1000);
+                self.0.read(&mut buf[..len])
+            }
+        }
+
+        let data = [SAMPLE_GZ, SAMPLE_GZ].concat();
+        let mut input_stream = DeflateChunkedBufferInput::new_double_buffered(
+            Trickle(std::io::Cursor::new(data.clone())),
+            1024 * 4,
+        );
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+        while input_stream.ensure_length(1) {
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        }
+        assert_eq!(input_stream.tell_stream_pos(), data.len());
+        assert_eq!(output_stream.into_inner(), [SAMPLE, SAMPLE].concat());
+
+        /* Errors of the source reach the decoder */
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("connection reset"))
+            }
+        }
+        let half = SAMPLE_GZ[..SAMPLE_GZ.len() / 2].to_vec();
+        let failing = std::io::Cursor::new(half).chain(Failing);
+        let mut input_stream = DeflateChunkedBufferInput::new_double_buffered(failing, 1024 * 4);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        assert!(matches!(
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream),
+            Err(LibdeflateError::ReadError(_))
+        ));
+    }
+
+    #[test]
+    fn gzip_header_errors() {
+        let mut bad_magic = SAMPLE_GZ.to_vec();
+        bad_magic[1] = 0;
+        assert!(matches!(gunzip(&bad_magic), Err(LibdeflateError::BadMagic)));
+
+        /* Add a FHCRC field after the 10 bytes fixed header */
+        let mut with_hcrc = SAMPLE_GZ.to_vec();
+        with_hcrc[3] |= GZIP_FHCRC;
+        let hcrc = crc32fast::hash(&with_hcrc[..GZIP_MIN_HEADER_SIZE]) as u16;
+        with_hcrc.splice(
+            GZIP_MIN_HEADER_SIZE..GZIP_MIN_HEADER_SIZE,
+            hcrc.to_le_bytes(),
+        );
+        assert_eq!(gunzip(&with_hcrc).unwrap(), SAMPLE);
+
+        with_hcrc[GZIP_MIN_HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            gunzip(&with_hcrc),
+            Err(LibdeflateError::HeaderCrcMismatch)
+        ));
+    }
+
+    #[test]
+    fn gzip_reserved_flags() {
+        let mut data = SAMPLE_GZ.to_vec();
+        data[3] |= GZIP_FTEXT | (GZIP_FRESERVED & 0x80);
+        assert!(matches!(gunzip(&data), Err(LibdeflateError::ReservedFlags)));
+
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+        decompressor.set_strict_mode(false);
+
+        let mut header = GzipHeader::default();
+        libdeflate_gzip_decompress_with_header(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+            &mut header,
+        )
+        .unwrap();
+        assert_eq!(output_stream.into_inner(), SAMPLE);
+        assert!(header.is_text);
+    }
+
+    fn gzip_with_header(name: &[u8], comment: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new()
+            .filename(name)
+            .comment(comment)
+            .mtime(1234)
+            .write(Vec::new(), flate2::Compression::default());
+        encoder.write_all(SAMPLE).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_header_fields() {
+        let data = gzip_with_header(
+            b"sample.txt",
+            "comment with multibyte \u{e9}\u{2713}".as_bytes(),
+        );
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        let mut header = GzipHeader::default();
+        libdeflate_gzip_decompress_with_header(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+            &mut header,
+        )
+        .unwrap();
+        assert_eq!(output_stream.into_inner(), SAMPLE);
+        assert_eq!(header.mtime, 1234);
+        assert_eq!(header.name.as_deref(), Some("sample.txt"));
+        assert_eq!(
+            header.comment.as_deref(),
+            Some("comment with multibyte \u{e9}\u{2713}")
+        );
+        assert!(header.extra.is_none());
+        assert!(!header.is_text);
+
+        /* Overlong strings are rejected only when they are kept */
+        let data = gzip_with_header(b"sample.txt", &vec![b'c'; GZIP_MAX_STRING_LEN + 1]);
+        assert_eq!(gunzip(&data).unwrap(), SAMPLE);
+
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        assert!(matches!(
+            libdeflate_gzip_decompress_with_header(
+                &mut decompressor,
+                &mut input_stream,
+                &mut output_stream,
+                &mut header,
+            ),
+            Err(LibdeflateError::BadData)
+        ));
+    }
+
+    #[test]
+    fn gzip_comment_file() {
+        let mut compressed = SAMPLE_COMMENT_GZ;
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        let mut header = GzipHeader::default();
+        libdeflate_gzip_decompress_with_header(
+            &mut decompressor,
+            &mut input_stream,
+            &mut output_stream,
+            &mut header,
+        )
+        .unwrap();
+        assert_eq!(header.name.as_deref(), Some("sample.txt"));
+        assert_eq!(header.comment.as_deref(), Some("caf\u{e9} \u{2713}"));
+        assert_eq!(output_stream.into_inner(), SAMPLE);
+    }
+
+    #[test]
+    fn gzip_result_fields() {
+        let data = [gzip_with_header(b"sample.txt", b""), SAMPLE_GZ.to_vec()].concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(std::io::sink(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        let first =
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        assert_eq!(first.header.name.as_deref(), Some("sample.txt"));
+        assert_eq!(first.header.mtime, 1234);
+        assert_eq!(
+            first.compressed_bytes,
+            (data.len() - SAMPLE_GZ.len()) as u64
+        );
+        assert_eq!(first.decompressed_bytes, SAMPLE.len() as u64);
+
+        let second =
+            libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)
+                .unwrap();
+        assert_eq!(second.compressed_bytes, SAMPLE_GZ.len() as u64);
+        assert_eq!(second.decompressed_bytes, SAMPLE.len() as u64);
+    }
+
+    #[test]
+    fn gzip_header_callback() {
+        let data = [
+            gzip_with_header(b"skip", b""),
+            gzip_with_header(b"keep", b""),
+        ]
+        .concat();
+        let mut compressed = &data[..];
+        let mut input_stream =
+            DeflateChunkedBufferInput::new(|buf| compressed.read(buf), 1024 * 64);
+        let mut output_stream = DeflateWriteOutput::new(Vec::new(), 1024 * 64);
+        let mut decompressor = libdeflate_alloc_decompressor();
+
+        let mut header = GzipHeader::default();
+        let mut decompress = |input_stream: &mut DeflateChunkedBufferInput| {
+            libdeflate_gzip_decompress_on_header(
+                &mut decompressor,
+                input_stream,
+                &mut output_stream,
+                &mut header,
+                |header| header.name.as_deref() == Some("keep"),
+            )
+        };
+        assert!(matches!(
+            decompress(&mut input_stream),
+            Err(LibdeflateError::HeaderRejected)
+        ));
+
+        /* The rejected member's data is still to be skipped */
+        let member_len = gzip_with_header(b"skip", b"").len();
+        let position = input_stream.tell_stream_pos();
+        assert!(input_stream.move_stream_pos((member_len - position) as isize));
+        assert_eq!(decompress(&mut input_stream).unwrap().written, SAMPLE.len());
+        assert_eq!(output_stream.into_inner(), SAMPLE);
+    }
+
+    #[test]
+    fn gzip_trailer_errors() {
+        let trailer = SAMPLE_GZ.len() - 8;
+        let crc = u32::from_le_bytes(SAMPLE_GZ[trailer..trailer + 4].try_into().unwrap());
+
+        let mut bad_crc = SAMPLE_GZ.to_vec();
+        bad_crc[trailer] ^= 1;
+        assert!(matches!(
+            gunzip(&bad_crc),
+            Err(LibdeflateError::ChecksumMismatch { expected, actual })
+                if expected == crc ^ 1 && actual == crc
+        ));
+
+        let mut bad_size = SAMPLE_GZ.to_vec();
+        bad_size[trailer + 4] ^= 1;
+        assert!(matches!(
+            gunzip(&bad_size),
+            Err(LibdeflateError::LengthMismatch { expected, actual })
+                if expected == (SAMPLE.len() as u32 ^ 1) && actual == SAMPLE.len() as u32
+        ));
+    }
+
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut compressor = flate2::Compress::new(flate2::Compression::default(), false);
+        let mut cdata = Vec::with_capacity(data.len() + 1024);
+        compressor
+            .compress_vec(data, &mut cdata, flate2::FlushCompress::Finish)
+            .unwrap();
+
+        let bsize = (18 + cdata.len() + 8 - 1) as u16;
+        let mut block = vec![
+            0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+        ];
+        block.extend_from_slice(&bsize.to_le_bytes());
+        block.extend_from_slice(&cdata);
+        block.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn bgzf_file_blocks() {
+        let blocks: Vec<_> = SAMPLE.chunks(65280).collect();
+        let mut bgzf: Vec<u8> = blocks.iter().flat_map(|block| bgzf_block(block)).collect();
+        bgzf.extend_from_slice(&bgzf_block(&[]));
+
+        let path = TempFile::new("bgzf_file_blocks");
+        std::fs::write(&path, &bgzf).unwrap();
+
+        let mut decompressed = Vec::new();
+        let mut indices = Vec::new();
+        let count = decompress_bgzf_file(&path, |index, data| {
+            indices.push(index);
+            decompressed.extend_from_slice(data);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(count, blocks.len() + 1);
+        assert_eq!(indices, (0..blocks.len()).collect::<Vec<_>>());
+        assert_eq!(decompressed, SAMPLE);
+    }
+
+    #[test]
+    fn overlong_codeword_lengths_rejected() {
+        let mut decompressor = libdeflate_alloc_decompressor();
+        let lens = &mut decompressor.l.lens;
+
+        /* Static Huffman code lengths, a valid complete code */
+        lens[..144].fill(8);
+        lens[144..256].fill(9);
+        lens[256..280].fill(7);
+        lens[280..288].fill(8);
+        lens[288..320].fill(5);
+        assert!(build_litlen_decode_table(
+            &mut decompressor,
+            DEFLATE_NUM_LITLEN_SYMS,
+            DEFLATE_NUM_OFFSET_SYMS
+        ));
+        assert!(build_offset_decode_table(
+            &mut decompressor,
+            DEFLATE_NUM_LITLEN_SYMS,
+            DEFLATE_NUM_OFFSET_SYMS
+        ));
+
+        for bad_len in (DEFLATE_MAX_LITLEN_CODEWORD_LEN + 1)..=u8::MAX as usize {
+            decompressor.l.lens[17] = bad_len as u8;
+            assert!(!build_litlen_decode_table(
+                &mut decompressor,
+                DEFLATE_NUM_LITLEN_SYMS,
+                DEFLATE_NUM_OFFSET_SYMS
+            ));
+        }
+        decompressor.l.lens[17] = 8;
+
+        for bad_len in (DEFLATE_MAX_OFFSET_CODEWORD_LEN + 1)..=u8::MAX as usize {
+            decompressor.l.lens[DEFLATE_NUM_LITLEN_SYMS + 3] = bad_len as u8;
+            assert!(!build_offset_decode_table(
+                &mut decompressor,
+                DEFLATE_NUM_LITLEN_SYMS,
+                DEFLATE_NUM_OFFSET_SYMS
+            ));
+        }
+    }
+
+    #[test]
+    fn decompressor_pool_reuse() {
+        let pool = DecompressorPool::new(2);
+        let first = pool.acquire();
+        let first_ptr = &*first as *const LibdeflateDecompressor;
+        pool.release(first);
+        assert_eq!(pool.idle_count(), 1);
+        let first = pool.acquire();
+        assert_eq!(&*first as *const LibdeflateDecompressor, first_ptr);
+
+        /* Only 'max_idle' are kept */
+        let all = [first, pool.acquire(), pool.acquire()];
+        assert_eq!(pool.idle_count(), 0);
+        for decompressor in all {
+            pool.release(decompressor);
+        }
+        assert_eq!(pool.idle_count(), 2);
+
+        let expected: usize = SAMPLE.iter().map(|b| *b as usize).sum();
+        (0..16).into_par_iter().for_each(|_| {
+            let mut decompressor = pool.acquire();
+            let mut sum = 0;
+            decompress_file_buffered_with_decompressor(
+                &mut decompressor,
+                "test_data/sample.txt.gz",
+                |data| {
+                    sum += data.iter().map(|b| *b as usize).sum::<usize>();
+                    Ok(())
+                },
+                1024 * 64,
+            )
+            .unwrap();
+            assert_eq!(sum, expected);
+            pool.release(decompressor);
+        });
+        assert!(pool.idle_count() <= 2);
+    }
+
+    #[test]
+    fn decode_table_memory_usage() {
+        let mut decompressor = libdeflate_alloc_decompressor();
+        let usage = decompressor.memory_usage();
+        assert_eq!(usage.litlen_subtable_entries_used, 0);
+        assert_eq!(usage.offset_subtable_entries_used, 0);
+
+        /* Codeword lengths 1, 2, ..., 14, 15, 15: the six codewords longer
+         * than LITLEN_TABLEBITS share a prefix, giving one 2^5 subtable */
+        let lens = &mut decompressor.l.lens;
+        for (sym, len) in lens[..15].iter_mut().enumerate() {
+            *len = sym as u8 + 1;
+        }
+        lens[15] = 15;
+        lens[288..320].fill(5);
+        assert!(build_litlen_decode_table(
+            &mut decompressor,
+            DEFLATE_NUM_LITLEN_SYMS,
+            DEFLATE_NUM_OFFSET_SYMS
+        ));
+        assert!(build_offset_decode_table(
+            &mut decompressor,
+            DEFLATE_NUM_LITLEN_SYMS,
+            DEFLATE_NUM_OFFSET_SYMS
+        ));
+
+        let usage = decompressor.memory_usage();
+        assert_eq!(usage.litlen_subtable_entries_used, 32);
+        assert_eq!(usage.offset_subtable_entries_used, 0);
+        assert!(usage.litlen_subtable_entries_used <= usage.litlen_subtable_capacity);
+        assert_eq!(
+            usage.static_bytes,
+            std::mem::size_of::<crate::LibdeflateDecompressor>()
+        );
+        assert!(usage.to_string().contains("litlen subtables 32/"));
+    }
+
+    #[test]
+    fn checked_copy_matches_rolling_copy() {
+        use crate::utils::{copy_rolling, copy_rolling_checked};
+
+        let prefix: Vec<u8> = (0..40u8).collect();
+        for offset in 1..=prefix.len() {
+            for length in 3..=64 {
+                for has_space in [false, true] {
+                    /* Room for the 3 words overrun of the fast path */
+                    let mut expected = prefix.clone();
+                    expected.resize(prefix.len() + length + 3 * 8, 0);
+                    let mut checked = expected.clone();
+
+                    unsafe {
+                        let dest = expected.as_mut_ptr().add(prefix.len());
+                        copy_rolling(dest, dest.add(length), offset, has_space);
+                    }
+                    copy_rolling_checked(&mut checked, prefix.len(), offset, length);
+
+                    let end = prefix.len() + length;
+                    assert_eq!(checked[..end], expected[..end]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn missing_file_error() {
+        let path = TempFile::new("missing_file_error");
+        let is_not_found = |result: Result<_, LibdeflateError>| matches!(result, Err(LibdeflateError::ReadError(err)) if err.kind() == std::io::ErrorKind::NotFound);
+
+        assert!(is_not_found(
+            decompress_file_buffered(&path, |_| Ok(()), 1024 * 64).map(|_| ())
+        ));
+        assert!(is_not_found(
+            decompress_gzip_lines(&path, |_| Ok(())).map(|_| ())
+        ));
+        assert!(is_not_found(
+            decompress_bgzf_file(&path, |_, _| Ok(())).map(|_| ())
+        ));
+    }
+
+    #[test]
+    fn decompress_chunks_roundtrip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/sample.txt.gz");
+        let mut chunks = DecompressChunks::new(path, 1024 * 64).unwrap();
+
+        let mut decompressed = Vec::new();
+        while let Some(chunk) = chunks.next_chunk() {
+            decompressed.extend_from_slice(chunk.unwrap());
+        }
+        assert_eq!(decompressed, SAMPLE);
+    }
+
+    #[test]
+    fn decompress_chunks_worker_panic() {
+        struct PanickingReader;
+        impl Read for PanickingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                panic!("reader failure");
+            }
+        }
+
+        let mut chunks = DecompressChunks::from_reader(PanickingReader, 1024 * 64);
+        assert!(matches!(
+            chunks.next_chunk(),
+            Some(Err(LibdeflateError::WorkerPanicked))
+        ));
+        assert!(chunks.next_chunk().is_none());
+    }
+
     #[test]
     fn decompression_speed() {
         let context = Arc::new(AtomicUsize::new(0));
@@ -167,11 +3500,14 @@ mod tests {
         paths_vec.sort();
         paths_vec.truncate(10000);
         let start = Instant::now();
+        let pool = DecompressorPool::new(rayon::current_num_threads());
 
         paths_vec.into_par_iter().for_each(|file| {
             let context = context.clone();
+            let mut decompressor = pool.acquire();
 
-            match decompress_file_buffered(
+            match decompress_file_buffered_with_decompressor(
+                &mut decompressor,
                 &file,
                 |data| {
                     let mut rem = 0;
@@ -188,6 +3524,7 @@ mod tests {
                     println!("Error: {}", file.display());
                 }
             }
+            pool.release(decompressor);
         });
 
         println!("Bench duration: {:.2}", start.elapsed().as_secs_f32());
@@ -0,0 +1,104 @@
+use crate::{DeflateInput, DeflateOutput};
+use std::cmp::min;
+
+/*
+ * Input reading directly from a memory region not owned by Rust, e.g. a
+ * buffer passed as a (pointer, length) pair through FFI.  Nothing is copied,
+ * and like DeflateFileBufferInput no read goes past the end of the region.
+ */
+pub struct DeflateAnonMemInput {
+    data: *const u8,
+    len: usize,
+    position: usize,
+}
+
+impl DeflateAnonMemInput {
+    /// # Safety
+    ///
+    /// 'data' must point to 'len' readable bytes that stay valid and
+    /// unmodified for the whole lifetime of the input.
+    pub unsafe fn new(data: *const u8, len: usize) -> Self {
+        Self {
+            data,
+            len,
+            position: 0,
+        }
+    }
+}
+
+impl DeflateInput for DeflateAnonMemInput {
+    #[inline(always)]
+    fn tell_stream_pos(&self) -> usize {
+        self.position
+    }
+
+    #[inline(always)]
+    unsafe fn get_le_word_no_advance(&mut self) -> usize {
+        usize::from_le_bytes(std::ptr::read_unaligned(
+            self.data.add(self.position) as *const [u8; std::mem::size_of::<usize>()]
+        ))
+    }
+
+    #[inline(always)]
+    fn move_stream_pos(&mut self, amount: isize) -> bool {
+        if amount > 0 {
+            if self.position + amount as usize > self.len {
+                return false;
+            }
+            self.position += amount as usize
+        } else {
+            self.position -= (-amount) as usize
+        }
+        true
+    }
+
+    #[inline(always)]
+    fn read(&mut self, out_data: &mut [u8]) -> usize {
+        let avail_bytes = min(out_data.len(), self.len - self.position);
+        unsafe {
+            self.read_unchecked(&mut out_data[0..avail_bytes]);
+        }
+        avail_bytes
+    }
+
+    #[inline(always)]
+    fn is_at_eof(&self) -> bool {
+        self.position >= self.len
+    }
+
+    #[inline(always)]
+    fn ensure_length(&mut self, len: usize) -> bool {
+        self.position + len <= self.len
+    }
+
+    #[inline(always)]
+    unsafe fn read_unchecked(&mut self, out_data: &mut [u8]) {
+        std::ptr::copy_nonoverlapping(
+            self.data.add(self.position),
+            out_data.as_mut_ptr(),
+            out_data.len(),
+        );
+        self.position += out_data.len();
+    }
+
+    #[inline(always)]
+    fn read_exact_into<O: DeflateOutput>(&mut self, out_stream: &mut O, mut length: usize) -> bool {
+        /* The whole region is available, so a single bounds check covers the block */
+        if !self.ensure_length(length) {
+            return false;
+        }
+        while length > 0 {
+            let buffer = out_stream.get_available_buffer();
+            if buffer.is_empty() {
+                return false;
+            }
+            let copyable = min(buffer.len(), length);
+            unsafe {
+                self.read_unchecked(&mut buffer[0..copyable]);
+                out_stream.advance_available_buffer_position(copyable);
+            }
+            length -= copyable;
+        }
+        true
+    }
+}
@@ -0,0 +1,146 @@
+use crate::deflate_constants::DEFLATE_MAX_MATCH_LEN;
+use crate::utils::copy_match;
+use crate::{DeflateOutput, OutStreamResult};
+use bytes::{Buf, Bytes, BytesMut};
+use crc32fast::Hasher;
+use std::cmp::min;
+use std::slice::from_raw_parts_mut;
+
+/*
+ * Output handing the decompressed data to a callback as Bytes chunks, for
+ * stacks built on the bytes crate.  Each flush freezes the filled part of the
+ * buffer and passes it on without copying it, then continues in a new buffer.
+ *
+ * Matches can reach back MAX_LOOK_BACK bytes, so that much of the flushed
+ * data must stay writable next to the new output and can't be shared: it is
+ * copied to the start of each new buffer and is not part of any chunk.  The
+ * chunks also keep their whole buffer alive, including the window bytes in
+ * front of them, until they are all dropped.
+ */
+pub struct DeflateBytesOutput<'a> {
+    buffer: BytesMut,
+    buf_size: usize,
+    lookback_pos: usize,
+    position: usize,
+    crc32: Hasher,
+    written: usize,
+    func: Box<dyn FnMut(Bytes) -> Result<(), ()> + 'a>,
+}
+
+impl<'a> DeflateBytesOutput<'a> {
+    /* 'buf_size' must be larger than MAX_LOOK_BACK plus a match: except for
+     * the first of each member, chunks are at most 'buf_size' - MAX_LOOK_BACK
+     * bytes */
+    pub fn new<F: FnMut(Bytes) -> Result<(), ()> + 'a>(chunk_func: F, buf_size: usize) -> Self {
+        assert!(
+            buf_size > Self::MAX_LOOK_BACK + DEFLATE_MAX_MATCH_LEN,
+            "bytes output buffer too small"
+        );
+        Self {
+            buffer: BytesMut::zeroed(buf_size),
+            buf_size,
+            lookback_pos: 0,
+            position: 0,
+            crc32: Hasher::new(),
+            written: 0,
+            func: Box::new(chunk_func),
+        }
+    }
+
+    fn flush_buffer(&mut self, ensure_size: usize) -> bool {
+        /* Start a new buffer with the trailing window bytes, they are needed
+         * by later matches */
+        let keep_buf_len = min(self.position, Self::MAX_LOOK_BACK);
+        let mut next = BytesMut::zeroed(self.buf_size);
+        next[..keep_buf_len]
+            .copy_from_slice(&self.buffer[self.position - keep_buf_len..self.position]);
+
+        let mut chunk = std::mem::replace(&mut self.buffer, next);
+        chunk.truncate(self.position);
+        chunk.advance(self.lookback_pos);
+        self.lookback_pos = keep_buf_len;
+        self.position = keep_buf_len;
+
+        if !chunk.is_empty() {
+            self.crc32.update(&chunk);
+            self.written += chunk.len();
+            if (self.func)(chunk.freeze()).is_err() {
+                return false;
+            }
+        }
+
+        self.buffer.len() - self.position > ensure_size
+    }
+}
+
+impl DeflateOutput for DeflateBytesOutput<'_> {
+    #[inline(always)]
+    fn copy_forward(&mut self, prev_offset: usize, length: usize) -> bool {
+        if self.buffer.len() - self.position <= length && !self.flush_buffer(length) {
+            return false;
+        }
+
+        if prev_offset > self.position {
+            return false;
+        }
+
+        unsafe {
+            copy_match(&mut self.buffer, self.position, prev_offset, length);
+        }
+        self.position += length;
+
+        true
+    }
+
+    #[inline(always)]
+    fn write(&mut self, data: &[u8]) -> bool {
+        if self.buffer.len() - self.position <= data.len() && !self.flush_buffer(data.len()) {
+            return false;
+        }
+        self.buffer[self.position..self.position + data.len()].copy_from_slice(data);
+        self.position += data.len();
+        true
+    }
+
+    #[inline(always)]
+    fn get_available_buffer(&mut self) -> &mut [u8] {
+        unsafe {
+            from_raw_parts_mut(
+                self.buffer.as_mut_ptr().add(self.position),
+                self.buffer.len() - self.position,
+            )
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn advance_available_buffer_position(&mut self, offset: usize) {
+        self.position += offset;
+        if self.buffer.len() == self.position {
+            self.flush_buffer(1);
+        }
+    }
+
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        Some(self.written + (self.position - self.lookback_pos))
+    }
+
+    #[inline(always)]
+    fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
+        if self.position > self.lookback_pos && !self.flush_buffer(0) {
+            return Err(());
+        }
+        self.position = 0;
+        self.lookback_pos = 0;
+
+        let result = OutStreamResult {
+            written: self.written,
+            crc32: self.crc32.clone().finalize(),
+            ..Default::default()
+        };
+
+        self.crc32 = Hasher::new();
+        self.written = 0;
+        Ok(result)
+    }
+}
@@ -1,24 +1,193 @@
+use crate::streams::stream_buffer::StreamBuffer;
 use crate::{DeflateInput, DeflateOutput};
 use nightly_quirks::utils::NightlyUtils;
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::io;
+#[cfg(feature = "double-buffer")]
+use std::io::Read;
+#[cfg(feature = "double-buffer")]
+use std::sync::mpsc::channel;
+
+/*
+ * Statistics about the refills performed by a DeflateChunkedBufferInput.  An
+ * overread happens when a refill can't provide the requested amount of bytes,
+ * e.g. when the decoder looks ahead past the end of the stream.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct InputTelemetry {
+    pub total_bytes_read: u64,
+    pub refill_count: u64,
+    pub overread_count: u64,
+    pub max_overread_bytes: usize,
+}
 
 pub struct DeflateChunkedBufferInput<'a> {
-    buffer: Box<[u8]>,
+    buffer: StreamBuffer<'a>,
+    /* Offset in the stream of the start of the buffer */
+    buffer_stream_pos: usize,
     position: usize,
     last_position: usize,
-    func: Box<dyn FnMut(&mut [u8]) -> usize + 'a>,
+    telemetry: InputTelemetry,
+    error: Option<io::Error>,
+    /* The read function failed, it won't be called again even once the error
+     * has been taken */
+    failed: bool,
+    /* The read function returned 0, it won't be called again */
+    at_eof: bool,
+    func: Box<dyn FnMut(&mut [u8]) -> io::Result<usize> + 'a>,
 }
 
 impl<'a> DeflateChunkedBufferInput<'a> {
-    pub fn new<F: FnMut(&mut [u8]) -> usize + 'a>(read_func: F, buf_size: usize) -> Self {
+    /*
+     * 'read_func' fills the given buffer like io::Read::read(), returning 0 at
+     * the end of the stream.  After an error no further reads are attempted,
+     * also once take_read_error() has reported it.
+     */
+    pub fn new<F: FnMut(&mut [u8]) -> io::Result<usize> + 'a>(
+        read_func: F,
+        buf_size: usize,
+    ) -> Self {
+        Self::with_input_buffer(
+            read_func,
+            StreamBuffer::owned(unsafe {
+                NightlyUtils::box_new_uninit_slice_assume_init(buf_size)
+            }),
+        )
+    }
+
+    /*
+     * Like new(), but uses the caller's 'buffer' instead of allocating one.
+     * The buffer must be able to hold the look-back bytes kept across refills
+     * plus a full word read.
+     */
+    pub fn new_with_buffer<F: FnMut(&mut [u8]) -> io::Result<usize> + 'a>(
+        read_func: F,
+        buffer: &'a mut [u8],
+    ) -> Self {
+        assert!(
+            buffer.len() >= 2 * Self::MAX_LOOK_BACK,
+            "input buffer too small"
+        );
+        Self::with_input_buffer(read_func, StreamBuffer::borrowed(buffer))
+    }
+
+    /*
+     * Like new(), reading the stream from an iterator over its bytes, e.g.
+     * from a parser that produces them one at a time.
+     */
+    pub fn new_from_iter<I: Iterator<Item = u8> + 'a>(mut source: I, buf_size: usize) -> Self {
+        Self::new(
+            move |buf| {
+                let mut count = 0;
+                for (dest, byte) in buf.iter_mut().zip(&mut source) {
+                    *dest = byte;
+                    count += 1;
+                }
+                Ok(count)
+            },
+            buf_size,
+        )
+    }
+
+    /*
+     * Like new(), but 'reader' is read ahead by a background thread into two
+     * 'buf_size' buffers: while the data of one is being decompressed the
+     * other is being filled, overlapping slow I/O (e.g. network filesystems)
+     * with decompression.  This costs an extra copy of the data into the
+     * input buffer.  The thread stops at the end of the stream, after a read
+     * error or, at the latest, one read after the input is dropped.
+     */
+    #[cfg(feature = "double-buffer")]
+    pub fn new_double_buffered<R: Read + Send + 'static>(mut reader: R, buf_size: usize) -> Self {
+        let (filled_send, filled_recv) = channel::<io::Result<(Box<[u8]>, usize)>>();
+        let (empty_send, empty_recv) = channel::<Box<[u8]>>();
+        for _ in 0..2 {
+            empty_send
+                .send(vec![0; buf_size].into_boxed_slice())
+                .unwrap();
+        }
+
+        std::thread::spawn(move || {
+            while let Ok(mut buffer) = empty_recv.recv() {
+                let mut filled = 0;
+                let mut error = None;
+                while filled < buffer.len() {
+                    match reader.read(&mut buffer[filled..]) {
+                        Ok(0) => break,
+                        Ok(count) => filled += count,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                        Err(err) => {
+                            error = Some(err);
+                            break;
+                        }
+                    }
+                }
+                /* An empty buffer marks the end of the stream, the data read
+                 * before an error is still delivered */
+                let at_end = filled == 0 && error.is_none();
+                if (filled > 0 || at_end) && filled_send.send(Ok((buffer, filled))).is_err() {
+                    break;
+                }
+                if let Some(err) = error {
+                    let _ = filled_send.send(Err(err));
+                    break;
+                }
+                if at_end {
+                    break;
+                }
+            }
+        });
+
+        let mut current: Option<(Box<[u8]>, usize)> = None;
+        let mut current_pos = 0;
+        Self::new(
+            move |buf| loop {
+                if let Some((data, len)) = &current {
+                    if current_pos < *len {
+                        let amount = min(buf.len(), len - current_pos);
+                        buf[..amount].copy_from_slice(&data[current_pos..current_pos + amount]);
+                        current_pos += amount;
+                        return Ok(amount);
+                    }
+                    if *len == 0 {
+                        return Ok(0);
+                    }
+                }
+                /* Hand the drained buffer back to be filled again */
+                if let Some((data, _)) = current.take() {
+                    let _ = empty_send.send(data);
+                }
+                current = Some(filled_recv.recv().map_err(|_| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "read-ahead thread stopped")
+                })??);
+                current_pos = 0;
+            },
+            buf_size,
+        )
+    }
+
+    fn with_input_buffer<F: FnMut(&mut [u8]) -> io::Result<usize> + 'a>(
+        read_func: F,
+        buffer: StreamBuffer<'a>,
+    ) -> Self {
         Self {
-            buffer: unsafe { NightlyUtils::box_new_uninit_slice_assume_init(buf_size) },
+            buffer,
+            buffer_stream_pos: 0,
             position: 0,
             last_position: 0,
+            telemetry: InputTelemetry::default(),
+            error: None,
+            failed: false,
+            at_eof: false,
             func: Box::new(read_func),
         }
     }
 
+    /* Returns the statistics collected so far and resets them */
+    pub fn take_telemetry(&mut self) -> InputTelemetry {
+        std::mem::take(&mut self.telemetry)
+    }
+
     fn refill_buffer(&mut self, min_amount: usize) -> bool {
         let keep_buf_len = min(self.position, Self::MAX_LOOK_BACK);
 
@@ -32,18 +201,57 @@ impl<'a> DeflateChunkedBufferInput<'a> {
                 move_amount,
             );
         }
+        self.buffer_stream_pos += move_offset;
         self.position -= move_offset;
         self.last_position -= move_offset;
 
-        let count = (self.func)(&mut self.buffer[self.last_position..]);
+        /* Short reads are common for pipes and sockets, keep reading until
+         * the buffer is full or the stream has ended */
+        while self.last_position < self.buffer.len() && !self.failed && !self.at_eof {
+            match (self.func)(&mut self.buffer[self.last_position..]) {
+                Ok(0) => self.at_eof = true,
+                Ok(count) => {
+                    self.last_position += count;
+                    self.telemetry.total_bytes_read += count as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.failed = true;
+                    self.error = Some(err);
+                }
+            }
+        }
+        self.telemetry.refill_count += 1;
 
-        self.last_position += count;
+        /* Word reads past the end of the stream see zeros instead of stale
+         * or uninitialized buffer bytes */
+        if self.at_eof {
+            let padding_end = min(self.last_position + Self::MAX_LOOK_BACK, self.buffer.len());
+            self.buffer[self.last_position..padding_end].fill(0);
+        }
+
+        let available = self.last_position - self.position;
+        if available < min_amount {
+            self.telemetry.overread_count += 1;
+            self.telemetry.max_overread_bytes =
+                max(self.telemetry.max_overread_bytes, min_amount - available);
+        }
 
-        (self.last_position - self.position) >= min_amount
+        available >= min_amount
     }
 }
 
 impl<'a> DeflateInput for DeflateChunkedBufferInput<'a> {
+    #[inline(always)]
+    fn take_read_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    #[inline(always)]
+    fn tell_stream_pos(&self) -> usize {
+        self.buffer_stream_pos + self.position
+    }
+
     #[inline(always)]
     unsafe fn get_le_word_no_advance(&mut self) -> usize {
         usize::from_le_bytes(
@@ -79,6 +287,25 @@ impl<'a> DeflateInput for DeflateChunkedBufferInput<'a> {
         avail_bytes
     }
 
+    /* A single refill can't provide more than the buffer holds, keep
+     * reading for larger requests */
+    #[inline(always)]
+    fn read_exact(&mut self, mut out_data: &mut [u8]) -> bool {
+        while !out_data.is_empty() {
+            let read = self.read(out_data);
+            if read == 0 {
+                return false;
+            }
+            out_data = &mut out_data[read..];
+        }
+        true
+    }
+
+    #[inline(always)]
+    fn is_at_eof(&self) -> bool {
+        self.at_eof && self.position >= self.last_position
+    }
+
     #[inline(always)]
     fn ensure_length(&mut self, len: usize) -> bool {
         if self.position + len > self.last_position {
@@ -102,12 +329,21 @@ impl<'a> DeflateInput for DeflateChunkedBufferInput<'a> {
     #[inline(always)]
     fn read_exact_into<O: DeflateOutput>(&mut self, out_stream: &mut O, mut length: usize) -> bool {
         while length > 0 {
+            if self.position >= self.last_position && !self.refill_buffer(1) {
+                return false;
+            }
             let buffer = out_stream.get_available_buffer();
-            let copyable = min(buffer.len(), length);
-            if self.read(&mut buffer[0..copyable]) != copyable {
+            if buffer.is_empty() {
                 return false;
             }
+            /* Copy the largest run that is contiguous in both buffers, refilling
+             * the input only once it has been fully drained */
+            let copyable = min(
+                min(buffer.len(), length),
+                self.last_position - self.position,
+            );
             unsafe {
+                self.read_unchecked(&mut buffer[0..copyable]);
                 out_stream.advance_available_buffer_position(copyable);
             }
             length -= copyable;
@@ -1,21 +1,46 @@
-use crate::utils::copy_rolling;
+use crate::decompress_deflate::{DEFLATE64_MAX_MATCH_LEN, DEFLATE64_MAX_WINDOW_SIZE};
+use crate::deflate_constants::DEFLATE_MAX_MATCH_LEN;
+use crate::streams::stream_buffer::StreamBuffer;
+use crate::utils::copy_match;
 use crate::{DeflateOutput, OutStreamResult};
 use mt_debug_counters::counter::{AtomicCounter, SumMode};
 use crc32fast::Hasher;
 use nightly_quirks::utils::NightlyUtils;
 use std::cmp::min;
-use std::mem::size_of;
+use std::io;
 use std::slice::from_raw_parts_mut;
 
 pub struct DeflateChunkedBufferOutput<'a> {
-    buffer: Box<[u8]>,
+    buffer: StreamBuffer<'a>,
     lookback_pos: usize,
     position: usize,
     crc32: Hasher,
     written: usize,
+    look_back: usize,
+    total_written: usize,
+    flush_count: u64,
+    max_output: usize,
+    quota_exceeded: bool,
+    line_aligned: bool,
+    min_flush_bytes: usize,
+    #[cfg(feature = "sha2")]
+    digest: Option<Box<dyn sha2::digest::DynDigest + 'a>>,
     func: Box<dyn FnMut(&[u8]) -> Result<(), ()> + 'a>,
 }
 
+/*
+ * Safe alternative to implementing DeflateOutput for a custom destination,
+ * e.g. a ring buffer or a mapped file, used through
+ * DeflateChunkedBufferOutput::new_with_sink().  The sink hands out regions
+ * to fill and is told how much of each one was written.
+ */
+pub trait DeflateSliceSink {
+    /* The next region to fill, an empty slice or an error stops decompression */
+    fn window(&mut self) -> io::Result<&mut [u8]>;
+    /* The first 'written' bytes of the last window hold decompressed data */
+    fn commit(&mut self, written: usize) -> io::Result<()>;
+}
+
 static COUNTER_THREADS_BUSY_READING: AtomicCounter<SumMode> =
     declare_counter_i64!("libdeflate_reading_threads", SumMode, false);
 
@@ -23,44 +48,325 @@ static COUNTER_THREADS_PROCESSING_READS: AtomicCounter<SumMode> =
     declare_counter_i64!("libdeflate_processing_threads", SumMode, false);
 
 impl<'a> DeflateChunkedBufferOutput<'a> {
+    /* Smallest buffer for with_buffer(): the window plus a match */
+    pub const MIN_BUFFER_SIZE: usize =
+        <Self as DeflateOutput>::MAX_LOOK_BACK + DEFLATE_MAX_MATCH_LEN + 1;
+
     pub fn new<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(write_func: F, buf_size: usize) -> Self {
+        Self::new_with_look_back(write_func, buf_size, Self::MAX_LOOK_BACK)
+    }
+
+    /*
+     * Like new(), but uses 'buffer' instead of allocating one, e.g. taken
+     * from a pool.  It must hold at least MIN_BUFFER_SIZE bytes, and can be
+     * taken back with into_buffer() to decompress the next stream.
+     */
+    pub fn with_buffer<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(
+        buffer: Box<[u8]>,
+        write_func: F,
+    ) -> Self {
+        assert!(
+            buffer.len() >= Self::MIN_BUFFER_SIZE,
+            "output buffer too small"
+        );
+        Self::with_output_buffer(write_func, StreamBuffer::owned(buffer), Self::MAX_LOOK_BACK)
+    }
+
+    /* Like with_buffer(), borrowing the caller's 'buffer' */
+    pub fn with_borrowed_buffer<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(
+        buffer: &'a mut [u8],
+        write_func: F,
+    ) -> Self {
+        assert!(
+            buffer.len() >= Self::MIN_BUFFER_SIZE,
+            "output buffer too small"
+        );
+        Self::with_output_buffer(
+            write_func,
+            StreamBuffer::borrowed(buffer),
+            Self::MAX_LOOK_BACK,
+        )
+    }
+
+    /*
+     * Give back the buffer passed to with_buffer(), or the one allocated by
+     * new(), None if it was borrowed.  Data not flushed by final_flush() is
+     * discarded.
+     */
+    pub fn into_buffer(mut self) -> Option<Box<[u8]>> {
+        std::mem::replace(&mut self.buffer, StreamBuffer::owned(Box::default())).into_owned()
+    }
+
+    /*
+     * Like new(), but each flushed chunk is passed to both 'first_func' and
+     * 'second_func', e.g. to hash the data while writing it.  The decoder
+     * writes matches directly into the output buffer, so the fan out can only
+     * happen at flush time: both sinks see the same chunks, and the second is
+     * not called if the first fails.
+     */
+    pub fn new_tee<
+        F1: FnMut(&[u8]) -> Result<(), ()> + 'a,
+        F2: FnMut(&[u8]) -> Result<(), ()> + 'a,
+    >(
+        mut first_func: F1,
+        mut second_func: F2,
+        buf_size: usize,
+    ) -> Self {
+        Self::new(
+            move |data| {
+                first_func(data)?;
+                second_func(data)
+            },
+            buf_size,
+        )
+    }
+
+    /*
+     * Like new(), but the flushed data is copied into the regions handed out
+     * by 'sink'.  Matches are resolved in the output's own buffer, so the
+     * windows need no slack for the copy overrun (up to 3 words past the end
+     * of a match) and can have any size.
+     */
+    pub fn new_with_sink<S: DeflateSliceSink>(sink: &'a mut S, buf_size: usize) -> Self {
+        Self::new(
+            move |mut data| {
+                while !data.is_empty() {
+                    let window = sink.window().map_err(|_| ())?;
+                    if window.is_empty() {
+                        return Err(());
+                    }
+                    let amount = min(window.len(), data.len());
+                    window[..amount].copy_from_slice(&data[..amount]);
+                    sink.commit(amount).map_err(|_| ())?;
+                    data = &data[amount..];
+                }
+                Ok(())
+            },
+            buf_size,
+        )
+    }
+
+    /*
+     * Like new(), but only keeps the last 'look_back' bytes across flushes.
+     * Useful when the stream is known to use a smaller window than the
+     * DEFLATE maximum; matches reaching further back are rejected as invalid,
+     * whether or not their data is still in the buffer.
+     */
+    pub fn new_with_look_back<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(
+        write_func: F,
+        buf_size: usize,
+        look_back: usize,
+    ) -> Self {
+        debug_assert!(look_back <= Self::MAX_LOOK_BACK);
+        Self::with_look_back_unchecked(write_func, buf_size, look_back)
+    }
+
+    /*
+     * Like new(), but keeps the DEFLATE64_MAX_WINDOW_SIZE window needed by
+     * the DEFLATE64 decompressor.  'buf_size' must be larger than the window
+     * plus DEFLATE64_MAX_MATCH_LEN.
+     */
+    pub fn new_deflate64<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(
+        write_func: F,
+        buf_size: usize,
+    ) -> Self {
+        debug_assert!(buf_size > DEFLATE64_MAX_WINDOW_SIZE + DEFLATE64_MAX_MATCH_LEN);
+        Self::with_look_back_unchecked(write_func, buf_size, DEFLATE64_MAX_WINDOW_SIZE)
+    }
+
+    fn with_look_back_unchecked<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(
+        write_func: F,
+        buf_size: usize,
+        look_back: usize,
+    ) -> Self {
+        Self::with_output_buffer(
+            write_func,
+            StreamBuffer::owned(unsafe {
+                NightlyUtils::box_new_uninit_slice_assume_init(buf_size)
+            }),
+            look_back,
+        )
+    }
+
+    fn with_output_buffer<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(
+        write_func: F,
+        buffer: StreamBuffer<'a>,
+        look_back: usize,
+    ) -> Self {
         COUNTER_THREADS_BUSY_READING.inc();
         Self {
-            buffer: unsafe { NightlyUtils::box_new_uninit_slice_assume_init(buf_size) },
+            buffer,
             lookback_pos: 0,
             position: 0,
             crc32: Hasher::new(),
             written: 0,
+            look_back,
+            total_written: 0,
+            flush_count: 0,
+            max_output: usize::MAX,
+            quota_exceeded: false,
+            line_aligned: false,
+            min_flush_bytes: 0,
+            #[cfg(feature = "sha2")]
+            digest: None,
             func: Box::new(write_func),
         }
     }
 
+    /*
+     * Limit the total amount of data flushed by this output, across members.
+     * Going past the limit makes decompression fail with InsufficientSpace.
+     */
+    pub fn set_max_output(&mut self, max_output: usize) {
+        self.max_output = max_output;
+    }
+
+    /*
+     * Flush only up to the last '\n' of the buffer, carrying the partial line
+     * over to the next flush, so that text consumers get whole lines.  Lines
+     * are still split when one doesn't fit in the buffer, and the last chunk
+     * of a member ends wherever the member does.
+     */
+    pub fn set_line_aligned(&mut self, line_aligned: bool) {
+        self.line_aligned = line_aligned;
+    }
+
+    /*
+     * Don't pass chunks smaller than 'min_bytes' to the callback, e.g. to
+     * avoid small writes to slow disks, when the look-back window takes most
+     * of the buffer.  The data is kept in the buffer as long as there is room
+     * for it, so chunks are still smaller if the buffer is.  The last chunk
+     * of a member is flushed whatever its size.
+     */
+    pub fn set_min_flush_bytes(&mut self, min_bytes: usize) {
+        self.min_flush_bytes = min_bytes;
+    }
+
+    /*
+     * Also feed the flushed data into 'digest', e.g. Box::new(Sha256::new()),
+     * reporting the digest of each member in OutStreamResult::digest.
+     */
+    #[cfg(feature = "sha2")]
+    pub fn set_digest(&mut self, digest: Box<dyn sha2::digest::DynDigest + 'a>) {
+        self.digest = Some(digest);
+    }
+
+    /*
+     * Bytes decompressed so far across members, including the ones still
+     * waiting in the buffer to be flushed, e.g. for progress reporting.
+     */
+    pub fn bytes_written_so_far(&self) -> usize {
+        self.total_written + (self.position - self.lookback_pos)
+    }
+
+    /* Number of times the buffered data has been passed to the callback */
+    pub fn flush_count(&self) -> u64 {
+        self.flush_count
+    }
+
+    /*
+     * Reset bytes_written_so_far() and flush_count(), e.g. when reusing the
+     * output for another file.  This also restarts the set_max_output() quota,
+     * discarding the data of a member stopped by it.
+     */
+    pub fn reset_counters(&mut self) {
+        self.total_written = 0;
+        self.flush_count = 0;
+        if self.quota_exceeded {
+            self.quota_exceeded = false;
+            self.position = 0;
+            self.lookback_pos = 0;
+            self.crc32 = Hasher::new();
+            self.written = 0;
+        }
+    }
+
+    #[cfg(feature = "sha2")]
+    fn member_digest(&mut self) -> Option<Box<[u8]>> {
+        self.digest.as_mut().map(|digest| digest.finalize_reset())
+    }
+
+    #[cfg(not(feature = "sha2"))]
+    fn member_digest(&mut self) -> Option<Box<[u8]>> {
+        None
+    }
+
+    /* The data kept after flushing up to 'flush_end': the window and whatever
+     * wasn't flushed */
+    fn keep_from(&self, flush_end: usize) -> usize {
+        min(self.position.saturating_sub(self.look_back), flush_end)
+    }
+
     fn flush_buffer(&mut self, ensure_size: usize) -> bool {
+        /* Only drop the history that left the window if that makes room */
+        if self.position - self.lookback_pos < self.min_flush_bytes
+            && self.buffer.len() - (self.position - self.keep_from(self.lookback_pos)) > ensure_size
+        {
+            self.compact_buffer(self.lookback_pos);
+            return true;
+        }
+
+        let mut flush_end = self.position;
+        if self.line_aligned {
+            let pending = &self.buffer[self.lookback_pos..self.position];
+            if let Some(newline) = pending.iter().rposition(|&b| b == b'\n') {
+                let line_end = self.lookback_pos + newline + 1;
+                /* Only if the partial line leaves enough room */
+                if self.buffer.len() - (self.position - self.keep_from(line_end)) > ensure_size {
+                    flush_end = line_end;
+                }
+            }
+        }
+        self.flush_buffer_until(flush_end, ensure_size)
+    }
+
+    fn flush_buffer_until(&mut self, flush_end: usize, ensure_size: usize) -> bool {
+        let flush_size = flush_end - self.lookback_pos;
+        if self.quota_exceeded || self.max_output.saturating_sub(self.total_written) < flush_size {
+            self.quota_exceeded = true;
+            return false;
+        }
+        /* Nothing new, e.g. at the end of an empty member: only the history
+         * is in the buffer, don't hand out an empty chunk */
+        if flush_size == 0 {
+            self.compact_buffer(flush_end);
+            return self.buffer.len() - self.position > ensure_size;
+        }
         self.crc32
-            .update(&self.buffer[self.lookback_pos..self.position]);
+            .update(&self.buffer[self.lookback_pos..flush_end]);
+        #[cfg(feature = "sha2")]
+        if let Some(digest) = self.digest.as_mut() {
+            digest.update(&self.buffer[self.lookback_pos..flush_end]);
+        }
         COUNTER_THREADS_BUSY_READING.sub(1);
         COUNTER_THREADS_PROCESSING_READS.inc();
-        if (self.func)(&self.buffer[self.lookback_pos..self.position]).is_err() {
+        if (self.func)(&self.buffer[self.lookback_pos..flush_end]).is_err() {
             COUNTER_THREADS_BUSY_READING.inc();
             COUNTER_THREADS_PROCESSING_READS.sub(1);
             return false;
         }
         COUNTER_THREADS_BUSY_READING.inc();
         COUNTER_THREADS_PROCESSING_READS.sub(1);
-        self.written += self.position - self.lookback_pos;
+        self.written += flush_size;
+        self.total_written += flush_size;
+        self.flush_count += 1;
+
+        self.compact_buffer(flush_end);
+        self.buffer.len() - self.position > ensure_size
+    }
 
-        let keep_buf_len = min(self.position, Self::MAX_LOOK_BACK);
+    /* Move the data kept after flushing up to 'flush_end' to the start */
+    fn compact_buffer(&mut self, flush_end: usize) {
+        let keep_from = self.keep_from(flush_end);
         unsafe {
             std::ptr::copy(
-                self.buffer.as_ptr().add(self.position - keep_buf_len),
+                self.buffer.as_ptr().add(keep_from),
                 self.buffer.as_mut_ptr(),
-                keep_buf_len,
+                self.position - keep_from,
             );
         }
-        self.lookback_pos = keep_buf_len;
-        self.position = keep_buf_len;
-
-        self.buffer.len() - self.position > ensure_size
+        self.lookback_pos = flush_end - keep_from;
+        self.position -= keep_from;
     }
 }
 
@@ -73,18 +379,14 @@ impl<'a> DeflateOutput for DeflateChunkedBufferOutput<'a> {
             }
         }
 
-        if prev_offset > self.position {
+        /* Also before the first flush, when more than the window is in the
+         * buffer */
+        if prev_offset > self.position || prev_offset > self.look_back {
             return false;
         }
 
         unsafe {
-            let dest = self.buffer.as_mut_ptr().add(self.position);
-            copy_rolling(
-                dest,
-                dest.add(length),
-                prev_offset,
-                self.get_available_buffer().len() >= (length + 3 * size_of::<usize>()),
-            );
+            copy_match(&mut self.buffer, self.position, prev_offset, length);
         }
         self.position += length;
 
@@ -121,21 +423,37 @@ impl<'a> DeflateOutput for DeflateChunkedBufferOutput<'a> {
         }
     }
 
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        Some(self.written + (self.position - self.lookback_pos))
+    }
+
     #[inline(always)]
     fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
-        self.flush_buffer(0);
+        self.flush_buffer_until(self.position, 0);
+        if self.quota_exceeded {
+            return Err(());
+        }
         self.position = 0;
         self.lookback_pos = 0;
 
         let result = OutStreamResult {
             written: self.written,
             crc32: self.crc32.clone().finalize(),
+            consumed: 0,
+            digest: self.member_digest(),
+            ..Default::default()
         };
 
         self.crc32 = Hasher::new();
         self.written = 0;
         Ok(result)
     }
+
+    #[inline(always)]
+    fn is_quota_exceeded(&self) -> bool {
+        self.quota_exceeded
+    }
 }
 
 impl Drop for DeflateChunkedBufferOutput<'_> {
@@ -18,6 +18,11 @@ impl DeflateFileBufferInput {
 }
 
 impl DeflateInput for DeflateFileBufferInput {
+    #[inline(always)]
+    fn tell_stream_pos(&self) -> usize {
+        self.position
+    }
+
     #[inline(always)]
     unsafe fn get_le_word_no_advance(&mut self) -> usize {
         usize::from_le_bytes(
@@ -44,6 +49,11 @@ impl DeflateInput for DeflateFileBufferInput {
         avail_bytes
     }
 
+    #[inline(always)]
+    fn is_at_eof(&self) -> bool {
+        self.position >= self.file.len()
+    }
+
     #[inline(always)]
     fn ensure_length(&mut self, len: usize) -> bool {
         self.position + len <= self.file.len()
@@ -61,13 +71,18 @@ impl DeflateInput for DeflateFileBufferInput {
 
     #[inline(always)]
     fn read_exact_into<O: DeflateOutput>(&mut self, out_stream: &mut O, mut length: usize) -> bool {
+        /* The whole file is mapped, so a single bounds check covers the block */
+        if !self.ensure_length(length) {
+            return false;
+        }
         while length > 0 {
             let buffer = out_stream.get_available_buffer();
-            let copyable = min(buffer.len(), length);
-            if self.read(&mut buffer[0..copyable]) != copyable {
+            if buffer.is_empty() {
                 return false;
             }
+            let copyable = min(buffer.len(), length);
             unsafe {
+                self.read_unchecked(&mut buffer[0..copyable]);
                 out_stream.advance_available_buffer_position(copyable);
             }
             length -= copyable;
@@ -0,0 +1,212 @@
+use crate::utils::copy_match;
+use crate::{DeflateOutput, LibdeflateError, OutStreamResult};
+use crc32fast::Hasher;
+use nightly_quirks::utils::NightlyUtils;
+use std::cmp::min;
+use std::slice::from_raw_parts_mut;
+
+/* Decompressed data buffered between two line splits, after the window */
+const LINE_OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+/* Longest line passed whole to the callback, see set_max_line_len() */
+pub const DEFAULT_MAX_LINE_LEN: usize = 16 * 1024 * 1024;
+
+type LineFunc<'a> = Box<dyn FnMut(&[u8]) -> Result<(), ()> + 'a>;
+
+/*
+ * Output passing the decompressed data to a callback one line at a time,
+ * including its terminating '\n' ("\r\n" line endings are kept whole).
+ * A line can span members: the data left after the last '\n' is carried over
+ * to the next member and only passed on by finish().  Lines longer than the
+ * maximum line length are cut into pieces, so that a file without newlines
+ * doesn't have to be held in memory.
+ */
+pub struct DeflateLineOutput<'a> {
+    buffer: Box<[u8]>,
+    lookback_pos: usize,
+    position: usize,
+    crc32: Hasher,
+    written: usize,
+    partial_line: Vec<u8>,
+    max_line_len: usize,
+    lines: usize,
+    failed: bool,
+    func: LineFunc<'a>,
+}
+
+impl<'a> DeflateLineOutput<'a> {
+    pub fn new<F: FnMut(&[u8]) -> Result<(), ()> + 'a>(line_func: F) -> Self {
+        Self {
+            buffer: unsafe {
+                NightlyUtils::box_new_uninit_slice_assume_init(
+                    Self::MAX_LOOK_BACK + LINE_OUTPUT_BUFFER_SIZE,
+                )
+            },
+            lookback_pos: 0,
+            position: 0,
+            crc32: Hasher::new(),
+            written: 0,
+            partial_line: Vec::new(),
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            lines: 0,
+            failed: false,
+            func: Box::new(line_func),
+        }
+    }
+
+    /*
+     * Pass lines longer than 'max_line_len' bytes to the callback in pieces of
+     * 'max_line_len' bytes, the last one ending the line.  Each piece counts
+     * as a line.
+     */
+    pub fn set_max_line_len(&mut self, max_line_len: usize) {
+        assert!(max_line_len > 0, "the maximum line length can't be 0");
+        self.max_line_len = max_line_len;
+    }
+
+    /* Number of lines passed to the callback so far */
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /*
+     * Pass the last line to the callback if the data doesn't end with '\n',
+     * returning the total number of lines.
+     */
+    pub fn finish(mut self) -> Result<usize, LibdeflateError> {
+        if self.failed {
+            return Err(LibdeflateError::InsufficientSpace);
+        }
+        if !self.partial_line.is_empty() {
+            (self.func)(&self.partial_line).map_err(|_| LibdeflateError::InsufficientSpace)?;
+            self.lines += 1;
+        }
+        Ok(self.lines)
+    }
+
+    fn flush_buffer(&mut self, ensure_size: usize) -> bool {
+        if self.failed {
+            return false;
+        }
+        let data = &self.buffer[self.lookback_pos..self.position];
+        self.crc32.update(data);
+
+        let mut line_start = 0;
+        loop {
+            let rest = &data[line_start..];
+            /* What can still be added to the carried over part of the line */
+            let room = self.max_line_len - self.partial_line.len();
+            let line_end = line_start
+                + match rest.iter().position(|&b| b == b'\n') {
+                    Some(newline) if newline < room => newline + 1,
+                    _ if rest.len() > room => room,
+                    _ => break,
+                };
+            let result = if self.partial_line.is_empty() {
+                (self.func)(&data[line_start..line_end])
+            } else {
+                self.partial_line
+                    .extend_from_slice(&data[line_start..line_end]);
+                let result = (self.func)(&self.partial_line);
+                self.partial_line.clear();
+                result
+            };
+            if result.is_err() {
+                self.failed = true;
+                return false;
+            }
+            self.lines += 1;
+            line_start = line_end;
+        }
+        self.partial_line.extend_from_slice(&data[line_start..]);
+        self.written += self.position - self.lookback_pos;
+
+        let keep_buf_len = min(self.position, Self::MAX_LOOK_BACK);
+        unsafe {
+            std::ptr::copy(
+                self.buffer.as_ptr().add(self.position - keep_buf_len),
+                self.buffer.as_mut_ptr(),
+                keep_buf_len,
+            );
+        }
+        self.lookback_pos = keep_buf_len;
+        self.position = keep_buf_len;
+
+        self.buffer.len() - self.position > ensure_size
+    }
+}
+
+impl<'a> DeflateOutput for DeflateLineOutput<'a> {
+    #[inline(always)]
+    fn copy_forward(&mut self, prev_offset: usize, length: usize) -> bool {
+        if self.buffer.len() - self.position <= length && !self.flush_buffer(length) {
+            return false;
+        }
+
+        if prev_offset > self.position {
+            return false;
+        }
+
+        unsafe {
+            copy_match(&mut self.buffer, self.position, prev_offset, length);
+        }
+        self.position += length;
+
+        true
+    }
+
+    #[inline(always)]
+    fn write(&mut self, data: &[u8]) -> bool {
+        if self.buffer.len() - self.position <= data.len() && !self.flush_buffer(data.len()) {
+            return false;
+        }
+        self.buffer[self.position..self.position + data.len()].copy_from_slice(data);
+        self.position += data.len();
+        true
+    }
+
+    #[inline(always)]
+    fn get_available_buffer(&mut self) -> &mut [u8] {
+        unsafe {
+            from_raw_parts_mut(
+                self.buffer.as_mut_ptr().add(self.position),
+                self.buffer.len() - self.position,
+            )
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn advance_available_buffer_position(&mut self, offset: usize) {
+        self.position += offset;
+        if self.buffer.len() == self.position {
+            self.flush_buffer(1);
+        }
+    }
+
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        Some(self.written + (self.position - self.lookback_pos))
+    }
+
+    #[inline(always)]
+    fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
+        self.flush_buffer(0);
+        if self.failed {
+            return Err(());
+        }
+        self.position = 0;
+        self.lookback_pos = 0;
+
+        let result = OutStreamResult {
+            written: self.written,
+            crc32: self.crc32.clone().finalize(),
+            consumed: 0,
+            digest: None,
+            ..Default::default()
+        };
+
+        self.crc32 = Hasher::new();
+        self.written = 0;
+        Ok(result)
+    }
+}
@@ -1,5 +1,5 @@
 use crate::decompress_deflate::OutStreamResult;
-use crate::utils::copy_rolling;
+use crate::utils::copy_match;
 use crate::DeflateOutput;
 use std::fs::File;
 use std::io::Write;
@@ -29,14 +29,15 @@ impl DeflateOutput for DeflateMemBufferOutput {
         self.buffer.reserve(length);
 
         unsafe {
-            let dest = self.buffer.as_mut_ptr().add(self.buffer.len());
-            copy_rolling(
-                dest,
-                dest.add(length),
+            let len = self.buffer.len();
+            let capacity = self.buffer.capacity();
+            copy_match(
+                from_raw_parts_mut(self.buffer.as_mut_ptr(), capacity),
+                len,
                 prev_offset,
-                self.get_available_buffer().len() > 3,
+                length,
             );
-            self.buffer.set_len(self.buffer.len() + length);
+            self.buffer.set_len(len + length);
         }
         true
     }
@@ -62,7 +63,6 @@ impl DeflateOutput for DeflateMemBufferOutput {
 
     #[inline(always)]
     fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
-        println!("Successfully read {} bytes!", self.buffer.len());
         File::create(&self.path)
             .unwrap()
             .write_all(&self.buffer)
@@ -70,6 +70,9 @@ impl DeflateOutput for DeflateMemBufferOutput {
         Ok(OutStreamResult {
             written: 0,
             crc32: 0,
+            consumed: 0,
+            digest: None,
+            ..Default::default()
         })
     }
 }
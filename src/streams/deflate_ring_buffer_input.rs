@@ -0,0 +1,187 @@
+use crate::{DeflateInput, DeflateOutput};
+use nightly_quirks::utils::NightlyUtils;
+use std::cmp::min;
+use std::io;
+use std::mem::size_of;
+
+/* The first bytes of the ring are mirrored past its end, so that a word read
+ * starting close to the end of the ring can wrap around without a branch */
+const MIRROR_SIZE: usize = size_of::<usize>();
+
+const MIN_RING_SIZE: usize = 64;
+
+type ReadFunc<'a> = Box<dyn FnMut(&mut [u8]) -> io::Result<usize> + 'a>;
+
+/*
+ * Like DeflateChunkedBufferInput, but the buffer is used as a ring: refills
+ * write after the last valid byte, wrapping around, instead of moving the
+ * look-back bytes to the front of the buffer.  Positions are absolute stream
+ * offsets, mapped into the ring by masking with its (power of two) size.
+ */
+pub struct DeflateRingBufferInput<'a> {
+    buffer: Box<[u8]>,
+    mask: usize,
+    position: usize,
+    last_position: usize,
+    error: Option<io::Error>,
+    /* The read function failed, it won't be called again */
+    failed: bool,
+    func: ReadFunc<'a>,
+}
+
+impl<'a> DeflateRingBufferInput<'a> {
+    /*
+     * 'read_func' has the same contract as for DeflateChunkedBufferInput.
+     * 'buf_size' is rounded up to a power of two.
+     */
+    pub fn new<F: FnMut(&mut [u8]) -> io::Result<usize> + 'a>(
+        read_func: F,
+        buf_size: usize,
+    ) -> Self {
+        let ring_size = buf_size.max(MIN_RING_SIZE).next_power_of_two();
+        Self {
+            buffer: unsafe {
+                NightlyUtils::box_new_uninit_slice_assume_init(ring_size + MIRROR_SIZE)
+            },
+            mask: ring_size - 1,
+            position: 0,
+            last_position: 0,
+            error: None,
+            failed: false,
+            func: Box::new(read_func),
+        }
+    }
+
+    #[inline(always)]
+    fn ring_size(&self) -> usize {
+        self.mask + 1
+    }
+
+    fn refill_buffer(&mut self, min_amount: usize) -> bool {
+        let ring_size = self.ring_size();
+        let keep_buf_len = min(self.position, Self::MAX_LOOK_BACK);
+
+        while !self.failed {
+            let free = ring_size - keep_buf_len - (self.last_position - self.position);
+            if free == 0 {
+                break;
+            }
+            let start = self.last_position & self.mask;
+            let amount = min(free, ring_size - start);
+
+            match (self.func)(&mut self.buffer[start..start + amount]) {
+                Ok(0) => break,
+                Ok(count) => {
+                    if start < MIRROR_SIZE {
+                        self.buffer.copy_within(0..MIRROR_SIZE, ring_size);
+                    }
+                    self.last_position += count;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.failed = true;
+                    self.error = Some(err);
+                }
+            }
+        }
+
+        (self.last_position - self.position) >= min_amount
+    }
+}
+
+impl<'a> DeflateInput for DeflateRingBufferInput<'a> {
+    #[inline(always)]
+    fn take_read_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    #[inline(always)]
+    fn tell_stream_pos(&self) -> usize {
+        self.position
+    }
+
+    #[inline(always)]
+    unsafe fn get_le_word_no_advance(&mut self) -> usize {
+        usize::from_le_bytes(std::ptr::read_unaligned(
+            self.buffer.as_ptr().add(self.position & self.mask) as *const [u8; size_of::<usize>()],
+        ))
+    }
+
+    #[inline(always)]
+    fn move_stream_pos(&mut self, amount: isize) -> bool {
+        if amount > 0 {
+            if self.position + amount as usize > self.last_position
+                && !self.refill_buffer(amount as usize)
+            {
+                return false;
+            }
+            self.position += amount as usize
+        } else {
+            self.position -= (-amount) as usize
+        }
+        self.position <= self.last_position
+    }
+
+    #[inline(always)]
+    fn read(&mut self, out_data: &mut [u8]) -> usize {
+        if self.last_position - self.position < out_data.len() {
+            self.refill_buffer(out_data.len());
+        }
+
+        let avail_bytes = min(out_data.len(), self.last_position - self.position);
+        unsafe {
+            self.read_unchecked(&mut out_data[0..avail_bytes]);
+        }
+        avail_bytes
+    }
+
+    #[inline(always)]
+    fn ensure_length(&mut self, len: usize) -> bool {
+        self.position + len <= self.last_position || self.refill_buffer(len)
+    }
+
+    #[inline(always)]
+    unsafe fn read_unchecked(&mut self, out_data: &mut [u8]) {
+        let start = self.position & self.mask;
+        let first_part = min(out_data.len(), self.ring_size() - start);
+
+        std::ptr::copy_nonoverlapping(
+            self.buffer.as_ptr().add(start),
+            out_data.as_mut_ptr(),
+            first_part,
+        );
+        std::ptr::copy_nonoverlapping(
+            self.buffer.as_ptr(),
+            out_data.as_mut_ptr().add(first_part),
+            out_data.len() - first_part,
+        );
+        self.position += out_data.len();
+    }
+
+    #[inline(always)]
+    fn read_exact_into<O: DeflateOutput>(&mut self, out_stream: &mut O, mut length: usize) -> bool {
+        while length > 0 {
+            if self.position >= self.last_position && !self.refill_buffer(1) {
+                return false;
+            }
+            let buffer = out_stream.get_available_buffer();
+            if buffer.is_empty() {
+                return false;
+            }
+            /* Stop each run at the end of the ring, so that it is contiguous */
+            let copyable = min(
+                min(buffer.len(), length),
+                min(
+                    self.last_position - self.position,
+                    self.ring_size() - (self.position & self.mask),
+                ),
+            );
+            unsafe {
+                self.read_unchecked(&mut buffer[0..copyable]);
+                out_stream.advance_available_buffer_position(copyable);
+            }
+            length -= copyable;
+        }
+        true
+    }
+}
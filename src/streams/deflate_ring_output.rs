@@ -0,0 +1,162 @@
+use crate::utils::copy_match;
+use crate::{DeflateOutput, OutStreamResult};
+use crc32fast::Hasher;
+use std::cmp::min;
+use std::slice::from_raw_parts_mut;
+
+/*
+ * Output with a fixed capacity, drained by a consumer that may take only part
+ * of the pending data at a time, e.g. a bounded channel or a hardware FIFO.
+ * Memory use doesn't depend on the size of the output.
+ *
+ * When the buffer is full the drain callback receives the data not yet
+ * drained and returns how many bytes it took, blocking as needed.  Drained
+ * bytes are reused once they are further back than the MAX_LOOK_BACK window.
+ * The window and the pending data are moved back to the start of the buffer
+ * instead of wrapping around, so that matches and their word over-writes
+ * always stay contiguous and within the buffer.
+ */
+pub struct DeflateRingOutput<'a> {
+    buffer: Box<[u8]>,
+    /* Start of the data not yet passed to the drain callback */
+    drained_pos: usize,
+    position: usize,
+    crc32: Hasher,
+    written: usize,
+    func: Box<DrainFunc<'a>>,
+}
+
+type DrainFunc<'a> = dyn FnMut(&[u8]) -> Result<usize, ()> + 'a;
+
+impl<'a> DeflateRingOutput<'a> {
+    /*
+     * 'drain_func' returns how many bytes of the given data it consumed.
+     * Taking none while the buffer is full, or an error, stops decompression.
+     * 'capacity' must hold at least twice the MAX_LOOK_BACK window.
+     */
+    pub fn new<F: FnMut(&[u8]) -> Result<usize, ()> + 'a>(drain_func: F, capacity: usize) -> Self {
+        assert!(
+            capacity >= 2 * Self::MAX_LOOK_BACK,
+            "ring output capacity too small"
+        );
+        Self {
+            buffer: vec![0; capacity].into_boxed_slice(),
+            drained_pos: 0,
+            position: 0,
+            crc32: Hasher::new(),
+            written: 0,
+            func: Box::new(drain_func),
+        }
+    }
+
+    /* Drain until more than 'ensure_size' bytes are free */
+    fn drain_buffer(&mut self, ensure_size: usize) -> bool {
+        loop {
+            let pending = &self.buffer[self.drained_pos..self.position];
+            let drained = if pending.is_empty() {
+                0
+            } else {
+                match (self.func)(pending) {
+                    Ok(drained) => min(drained, pending.len()),
+                    Err(()) => return false,
+                }
+            };
+            self.crc32
+                .update(&self.buffer[self.drained_pos..self.drained_pos + drained]);
+            self.drained_pos += drained;
+            self.written += drained;
+
+            /* Keep the window and the pending data */
+            let keep_from = min(
+                self.drained_pos,
+                self.position.saturating_sub(Self::MAX_LOOK_BACK),
+            );
+            self.buffer.copy_within(keep_from..self.position, 0);
+            self.drained_pos -= keep_from;
+            self.position -= keep_from;
+
+            if self.buffer.len() - self.position > ensure_size {
+                return true;
+            }
+            if drained == 0 {
+                return false;
+            }
+        }
+    }
+}
+
+impl DeflateOutput for DeflateRingOutput<'_> {
+    #[inline(always)]
+    fn copy_forward(&mut self, prev_offset: usize, length: usize) -> bool {
+        if self.buffer.len() - self.position <= length && !self.drain_buffer(length) {
+            return false;
+        }
+
+        if prev_offset > self.position {
+            return false;
+        }
+
+        unsafe {
+            copy_match(&mut self.buffer, self.position, prev_offset, length);
+        }
+        self.position += length;
+
+        true
+    }
+
+    #[inline(always)]
+    fn write(&mut self, data: &[u8]) -> bool {
+        if self.buffer.len() - self.position <= data.len() && !self.drain_buffer(data.len()) {
+            return false;
+        }
+        self.buffer[self.position..self.position + data.len()].copy_from_slice(data);
+        self.position += data.len();
+        true
+    }
+
+    #[inline(always)]
+    fn get_available_buffer(&mut self) -> &mut [u8] {
+        unsafe {
+            from_raw_parts_mut(
+                self.buffer.as_mut_ptr().add(self.position),
+                self.buffer.len() - self.position,
+            )
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn advance_available_buffer_position(&mut self, offset: usize) {
+        self.position += offset;
+        if self.buffer.len() == self.position {
+            self.drain_buffer(0);
+        }
+    }
+
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        Some(self.written + (self.position - self.drained_pos))
+    }
+
+    #[inline(always)]
+    fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
+        while self.drained_pos < self.position {
+            let pending = self.position - self.drained_pos;
+            self.drain_buffer(0);
+            if self.position - self.drained_pos == pending {
+                return Err(());
+            }
+        }
+        self.position = 0;
+        self.drained_pos = 0;
+
+        let result = OutStreamResult {
+            written: self.written,
+            crc32: self.crc32.clone().finalize(),
+            ..Default::default()
+        };
+
+        self.crc32 = Hasher::new();
+        self.written = 0;
+        Ok(result)
+    }
+}
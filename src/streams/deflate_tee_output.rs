@@ -0,0 +1,106 @@
+use crate::{DeflateOutput, OutStreamResult};
+
+/*
+ * Output writing the decompressed data to two outputs at once, e.g. a file
+ * and a hashing sink.  Each output keeps its own window, so matches are
+ * copied in both; data the decoder writes directly into the first output's
+ * buffer is then written to the second one.
+ */
+pub struct DeflateTeeOutput<A: DeflateOutput, B: DeflateOutput> {
+    first: A,
+    second: B,
+    /* Writing to 'second' failed while the decoder was filling 'first' */
+    failed: bool,
+}
+
+impl<A: DeflateOutput, B: DeflateOutput> DeflateTeeOutput<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            failed: false,
+        }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A: DeflateOutput, B: DeflateOutput, C: DeflateOutput>
+    DeflateTeeOutput<A, DeflateTeeOutput<B, C>>
+{
+    /* Like new(), writing to three outputs */
+    pub fn new3(first: A, second: B, third: C) -> Self {
+        Self::new(first, DeflateTeeOutput::new(second, third))
+    }
+}
+
+impl<A: DeflateOutput, B: DeflateOutput> DeflateOutput for DeflateTeeOutput<A, B> {
+    #[inline(always)]
+    fn copy_forward(&mut self, prev_offset: usize, length: usize) -> bool {
+        !self.failed
+            && self.first.copy_forward(prev_offset, length)
+            && self.second.copy_forward(prev_offset, length)
+    }
+
+    #[inline(always)]
+    fn write(&mut self, data: &[u8]) -> bool {
+        !self.failed && self.first.write(data) && self.second.write(data)
+    }
+
+    #[inline(always)]
+    fn get_available_buffer(&mut self) -> &mut [u8] {
+        if self.failed {
+            return &mut [];
+        }
+        self.first.get_available_buffer()
+    }
+
+    #[inline(always)]
+    unsafe fn advance_available_buffer_position(&mut self, offset: usize) {
+        /* The second output may have less room than the first, copy through
+         * its own buffer.  A failure can't be reported here, stop accepting
+         * data instead. */
+        let mut data = &self.first.get_available_buffer()[..offset];
+        while !data.is_empty() {
+            let buffer = self.second.get_available_buffer();
+            if buffer.is_empty() {
+                self.failed = true;
+                break;
+            }
+            let amount = buffer.len().min(data.len());
+            buffer[..amount].copy_from_slice(&data[..amount]);
+            self.second.advance_available_buffer_position(amount);
+            data = &data[amount..];
+        }
+        self.first.advance_available_buffer_position(offset);
+    }
+
+    #[inline(always)]
+    fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
+        let result = self.first.final_flush();
+        self.second.final_flush()?;
+        if self.failed {
+            return Err(());
+        }
+        result
+    }
+
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        self.first.member_written()
+    }
+
+    #[inline(always)]
+    fn is_quota_exceeded(&self) -> bool {
+        self.first.is_quota_exceeded() || self.second.is_quota_exceeded()
+    }
+
+    #[inline(always)]
+    fn take_write_error(&mut self) -> Option<std::io::Error> {
+        self.first
+            .take_write_error()
+            .or_else(|| self.second.take_write_error())
+    }
+}
@@ -0,0 +1,64 @@
+use crate::streams::deflate_ring_output::DeflateRingOutput;
+use crate::{DeflateOutput, OutStreamResult};
+
+/*
+ * Output that only checks a stream: it computes the CRC32 and the size of each
+ * member, as needed to validate its trailer, but the data never leaves the
+ * decoder.  It is a DeflateRingOutput whose drain takes everything, so only
+ * the MAX_LOOK_BACK window is kept and the bytes are hashed as they leave it.
+ */
+pub struct DeflateVerifyOutput {
+    ring: DeflateRingOutput<'static>,
+}
+
+impl DeflateVerifyOutput {
+    /*
+     * The window is moved back to the start of the buffer once the buffer is
+     * full, a larger buffer moves it less often.
+     */
+    pub const BUFFER_SIZE: usize = 8 * Self::MAX_LOOK_BACK;
+
+    pub fn new() -> Self {
+        Self {
+            ring: DeflateRingOutput::new(|data| Ok(data.len()), Self::BUFFER_SIZE),
+        }
+    }
+}
+
+impl Default for DeflateVerifyOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeflateOutput for DeflateVerifyOutput {
+    #[inline(always)]
+    fn copy_forward(&mut self, prev_offset: usize, length: usize) -> bool {
+        self.ring.copy_forward(prev_offset, length)
+    }
+
+    #[inline(always)]
+    fn write(&mut self, data: &[u8]) -> bool {
+        self.ring.write(data)
+    }
+
+    #[inline(always)]
+    fn get_available_buffer(&mut self) -> &mut [u8] {
+        self.ring.get_available_buffer()
+    }
+
+    #[inline(always)]
+    unsafe fn advance_available_buffer_position(&mut self, offset: usize) {
+        self.ring.advance_available_buffer_position(offset)
+    }
+
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        self.ring.member_written()
+    }
+
+    #[inline(always)]
+    fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
+        self.ring.final_flush()
+    }
+}
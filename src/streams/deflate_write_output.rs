@@ -0,0 +1,114 @@
+use crate::streams::deflate_chunked_buffer_output::DeflateChunkedBufferOutput;
+use crate::{DeflateOutput, OutStreamResult};
+use std::cell::{Ref, RefCell, RefMut};
+use std::io::{self, Write};
+use std::rc::Rc;
+
+struct WriterState<W> {
+    writer: W,
+    /* Set by the first failed write, the following flushes fail without
+     * writing even once the error has been taken */
+    failed: bool,
+    error: Option<io::Error>,
+}
+
+/*
+ * Output writing the decompressed data to 'W'.  It is a
+ * DeflateChunkedBufferOutput flushing into the writer, which keeps the
+ * MAX_LOOK_BACK window; the writer is shared with the flush callback so that
+ * it can be reached and taken back.  A failed write stops decompression with
+ * LibdeflateError::WriteError.
+ */
+pub struct DeflateWriteOutput<'a, W: Write + 'a> {
+    output: DeflateChunkedBufferOutput<'a>,
+    state: Rc<RefCell<WriterState<W>>>,
+}
+
+impl<'a, W: Write + 'a> DeflateWriteOutput<'a, W> {
+    pub fn new(writer: W, buf_size: usize) -> Self {
+        let state = Rc::new(RefCell::new(WriterState {
+            writer,
+            failed: false,
+            error: None,
+        }));
+        let flush_state = state.clone();
+        let output = DeflateChunkedBufferOutput::new(
+            move |data| {
+                let mut state = flush_state.borrow_mut();
+                if state.failed {
+                    return Err(());
+                }
+                state.writer.write_all(data).map_err(|err| {
+                    state.failed = true;
+                    state.error = Some(err);
+                })
+            },
+            buf_size,
+        );
+        Self { output, state }
+    }
+
+    pub fn get_ref(&self) -> Ref<'_, W> {
+        Ref::map(self.state.borrow(), |state| &state.writer)
+    }
+
+    pub fn get_mut(&mut self) -> RefMut<'_, W> {
+        RefMut::map(self.state.borrow_mut(), |state| &mut state.writer)
+    }
+
+    pub fn into_inner(self) -> W {
+        /* Drops the flush callback, the other owner of the state */
+        drop(self.output);
+        match Rc::try_unwrap(self.state) {
+            Ok(state) => state.into_inner().writer,
+            Err(_) => unreachable!("the writer is still shared"),
+        }
+    }
+}
+
+impl<'a, W: Write + 'a> DeflateOutput for DeflateWriteOutput<'a, W> {
+    #[inline(always)]
+    fn copy_forward(&mut self, prev_offset: usize, length: usize) -> bool {
+        self.output.copy_forward(prev_offset, length)
+    }
+
+    #[inline(always)]
+    fn write(&mut self, data: &[u8]) -> bool {
+        self.output.write(data)
+    }
+
+    #[inline(always)]
+    fn get_available_buffer(&mut self) -> &mut [u8] {
+        self.output.get_available_buffer()
+    }
+
+    #[inline(always)]
+    unsafe fn advance_available_buffer_position(&mut self, offset: usize) {
+        self.output.advance_available_buffer_position(offset)
+    }
+
+    #[inline(always)]
+    fn member_written(&self) -> Option<usize> {
+        self.output.member_written()
+    }
+
+    fn final_flush(&mut self) -> Result<OutStreamResult, ()> {
+        let result = self.output.final_flush()?;
+        let mut state = self.state.borrow_mut();
+        if !state.failed {
+            if let Err(err) = state.writer.flush() {
+                state.failed = true;
+                state.error = Some(err);
+            }
+        }
+        if state.failed {
+            return Err(());
+        }
+        Ok(result)
+    }
+
+    #[inline(always)]
+    fn take_write_error(&mut self) -> Option<io::Error> {
+        self.state.borrow_mut().error.take()
+    }
+}
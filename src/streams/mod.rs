@@ -1,4 +1,15 @@
+pub mod deflate_anon_mem_input;
+#[cfg(feature = "bytes")]
+pub mod deflate_bytes_output;
 pub mod deflate_chunked_buffer_input;
 pub mod deflate_chunked_buffer_output;
+#[cfg(not(target_family = "wasm"))]
 pub mod deflate_filebuffer_input;
+pub mod deflate_line_output;
 pub mod deflate_membuffer_output;
+pub mod deflate_ring_buffer_input;
+pub mod deflate_ring_output;
+pub mod deflate_tee_output;
+pub mod deflate_verify_output;
+pub mod deflate_write_output;
+mod stream_buffer;
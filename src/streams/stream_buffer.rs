@@ -0,0 +1,70 @@
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+/*
+ * Buffer of a stream, either allocated by the stream or borrowed from the
+ * caller.  The slice is resolved once at construction, so that the accesses
+ * in the decoding loop don't depend on where the buffer comes from; 'owned'
+ * only matters when the buffer is given back or dropped.
+ */
+pub(crate) struct StreamBuffer<'a> {
+    data: NonNull<[u8]>,
+    /* Allocated by a Box, freed on drop */
+    owned: bool,
+    _borrow: PhantomData<&'a mut [u8]>,
+}
+
+/* Either a Box or a mutable borrow, both of which are Send and Sync */
+unsafe impl Send for StreamBuffer<'_> {}
+unsafe impl Sync for StreamBuffer<'_> {}
+
+impl<'a> StreamBuffer<'a> {
+    pub(crate) fn owned(buffer: Box<[u8]>) -> Self {
+        Self {
+            data: NonNull::from(Box::leak(buffer)),
+            owned: true,
+            _borrow: PhantomData,
+        }
+    }
+
+    pub(crate) fn borrowed(buffer: &'a mut [u8]) -> Self {
+        Self {
+            data: NonNull::from(buffer),
+            owned: false,
+            _borrow: PhantomData,
+        }
+    }
+
+    /* Give back an owned buffer, None if it was borrowed */
+    pub(crate) fn into_owned(self) -> Option<Box<[u8]>> {
+        let buffer = std::mem::ManuallyDrop::new(self);
+        buffer
+            .owned
+            .then(|| unsafe { Box::from_raw(buffer.data.as_ptr()) })
+    }
+}
+
+impl Deref for StreamBuffer<'_> {
+    type Target = [u8];
+
+    #[inline(always)]
+    fn deref(&self) -> &[u8] {
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl DerefMut for StreamBuffer<'_> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { self.data.as_mut() }
+    }
+}
+
+impl Drop for StreamBuffer<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            drop(unsafe { Box::from_raw(self.data.as_ptr()) });
+        }
+    }
+}
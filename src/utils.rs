@@ -1,7 +1,14 @@
+/* With bounds-checked-copy, copy_match() doesn't use the raw pointer copy */
+#![cfg_attr(feature = "bounds-checked-copy", allow(dead_code))]
+
 use crate::deflate_constants::DEFLATE_MIN_MATCH_LEN;
 use nightly_quirks::branch_pred::likely;
 
 const WORD_BYTES: usize = std::mem::size_of::<usize>();
+const DWORD_BYTES: usize = 2 * WORD_BYTES;
+
+/* Shortest match copied with double word moves, when they don't overlap */
+const DWORD_COPY_MIN_LEN: usize = 40;
 
 #[inline(always)]
 unsafe fn copy_word_unaligned(src: *const u8, dst: *mut u8) {
@@ -11,6 +18,14 @@ unsafe fn copy_word_unaligned(src: *const u8, dst: *mut u8) {
     );
 }
 
+#[inline(always)]
+unsafe fn copy_dword_unaligned(src: *const u8, dst: *mut u8) {
+    std::ptr::write_unaligned(
+        dst as *mut [usize; 2],
+        std::ptr::read_unaligned(src as *const [usize; 2]),
+    );
+}
+
 #[inline(always)]
 pub unsafe fn copy_rolling(mut dst: *mut u8, dst_end: *const u8, offset: usize, has_space: bool) {
     let mut src = dst.offset(-(offset as isize)) as *const u8;
@@ -18,7 +33,18 @@ pub unsafe fn copy_rolling(mut dst: *mut u8, dst_end: *const u8, offset: usize,
     if
     /* max overrun is writing 3 words for a min length match */
     likely(has_space) {
-        if offset >= WORD_BYTES {
+        if offset >= DWORD_BYTES && dst_end as usize - dst as usize >= DWORD_COPY_MIN_LEN {
+            /* long match, double words don't overlap: overrun is less than a
+             * double word, within the space for 3 words */
+            loop {
+                copy_dword_unaligned(src, dst);
+                src = src.add(DWORD_BYTES);
+                dst = dst.add(DWORD_BYTES);
+                if dst as usize >= dst_end as usize {
+                    break;
+                }
+            }
+        } else if offset >= WORD_BYTES {
             /* words don't overlap? */
             copy_word_unaligned(src, dst);
             src = src.add(WORD_BYTES);
@@ -82,3 +108,43 @@ pub unsafe fn copy_rolling(mut dst: *mut u8, dst_end: *const u8, offset: usize,
         }
     }
 }
+
+/*
+ * Same result as copy_rolling(), but indexing 'buffer' with explicit bounds
+ * checks instead of raw pointer arithmetic.  Slower, but it can run under
+ * Miri and turns a wrong offset or length into a panic.
+ */
+#[cfg(any(test, feature = "bounds-checked-copy"))]
+pub fn copy_rolling_checked(buffer: &mut [u8], position: usize, offset: usize, length: usize) {
+    assert!(offset >= 1 && position >= offset);
+    assert!(position + length <= buffer.len());
+
+    let mut dst = position;
+    while dst < position + length {
+        buffer[dst] = buffer[dst - offset];
+        dst += 1;
+    }
+}
+
+/*
+ * Copy a 'length' bytes match starting 'offset' bytes before 'position' in
+ * 'buffer', possibly overlapping.  The caller must ensure that the match fits
+ * in 'buffer' and that 'offset' doesn't reach before its start.
+ */
+#[inline(always)]
+pub unsafe fn copy_match(buffer: &mut [u8], position: usize, offset: usize, length: usize) {
+    #[cfg(any(test, feature = "bounds-checked-copy"))]
+    copy_rolling_checked(buffer, position, offset, length);
+
+    #[cfg(not(any(test, feature = "bounds-checked-copy")))]
+    {
+        let dest = buffer.as_mut_ptr().add(position);
+        copy_rolling(
+            dest,
+            dest.add(length),
+            offset,
+            /* max overrun is writing 3 words for a min length match */
+            buffer.len() - position >= length + 3 * WORD_BYTES,
+        );
+    }
+}
@@ -0,0 +1,21 @@
+/*
+ * Entry points for in-browser decompression, built with
+ *
+ *   cargo build --release --target wasm32-unknown-unknown --features wasm
+ *
+ * and bound with wasm-bindgen.  WebAssembly allows unaligned loads and
+ * stores, so the word at a time bit buffer refills and match copies work
+ * unchanged with the 32-bit words of wasm32.
+ */
+
+use crate::decompress_gzip::gunzip_to_vec;
+use wasm_bindgen::prelude::*;
+
+/*
+ * Decompress all the gzip members in 'input'.  Errors are thrown as a JS
+ * Error with the LibdeflateError variant as its message.
+ */
+#[wasm_bindgen]
+pub fn gunzip(input: &[u8]) -> Result<Vec<u8>, JsError> {
+    gunzip_to_vec(input).map_err(|err| JsError::new(&format!("{:?}", err)))
+}
@@ -0,0 +1,205 @@
+/*
+ * Differential tests: every stream is decompressed both by this crate and by
+ * the reference zlib inflate (through flate2's libz-sys backend), and the
+ * outputs must match byte for byte.
+ */
+
+use flate2::write::GzEncoder;
+use flate2::{Compression, Crc, Decompress, FlushDecompress, Status};
+use std::io::{Read, Write};
+use streaming_libdeflate_rs::decompress_gzip::libdeflate_gzip_decompress;
+use streaming_libdeflate_rs::libdeflate_alloc_decompressor;
+use streaming_libdeflate_rs::streams::deflate_chunked_buffer_input::DeflateChunkedBufferInput;
+use streaming_libdeflate_rs::streams::deflate_write_output::DeflateWriteOutput;
+use streaming_libdeflate_rs::{DeflateInput, LibdeflateError};
+
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_FOOTER_SIZE: usize = 8;
+const BUF_SIZE: usize = 1024 * 64;
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+    let mut rng = XorShift(seed);
+    (0..len).map(|_| rng.next() as u8).collect()
+}
+
+fn english_text(len: usize) -> Vec<u8> {
+    const WORDS: [&str; 12] = [
+        "the",
+        "quick",
+        "brown",
+        "fox",
+        "jumps",
+        "over",
+        "a",
+        "lazy",
+        "dog",
+        "while",
+        "streaming",
+        "data",
+    ];
+    let mut rng = XorShift(0x5eed);
+    let mut text = Vec::with_capacity(len + 16);
+    while text.len() < len {
+        text.extend_from_slice(WORDS[rng.next() as usize % WORDS.len()].as_bytes());
+        text.push(if rng.next().is_multiple_of(10) {
+            b'\n'
+        } else {
+            b' '
+        });
+    }
+    text.truncate(len);
+    text
+}
+
+fn dna_sequence(len: usize) -> Vec<u8> {
+    let mut rng = XorShift(0xac67);
+    (0..len).map(|_| b"ACGT"[rng.next() as usize % 4]).collect()
+}
+
+fn repeated_pattern(len: usize) -> Vec<u8> {
+    b"0123456789abcdef-"
+        .iter()
+        .cycle()
+        .take(len)
+        .copied()
+        .collect()
+}
+
+fn gzip(data: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn crate_gunzip(data: &[u8]) -> Result<Vec<u8>, LibdeflateError> {
+    let mut compressed = data;
+    let mut input_stream = DeflateChunkedBufferInput::new(|buf| compressed.read(buf), BUF_SIZE);
+    let mut output_stream = DeflateWriteOutput::new(Vec::new(), BUF_SIZE);
+    let mut decompressor = libdeflate_alloc_decompressor();
+
+    while input_stream.ensure_length(1) {
+        libdeflate_gzip_decompress(&mut decompressor, &mut input_stream, &mut output_stream)?;
+    }
+    Ok(output_stream.into_inner())
+}
+
+/* Raw DEFLATE inflate with zlib, returning the output and the consumed bytes */
+fn zlib_inflate_raw(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut inflater = Decompress::new(false);
+    let mut output = Vec::with_capacity(data.len() * 4 + 1024);
+    loop {
+        let in_pos = inflater.total_in() as usize;
+        match inflater.decompress_vec(&data[in_pos..], &mut output, FlushDecompress::Finish) {
+            Ok(Status::StreamEnd) => return Some((output, inflater.total_in() as usize)),
+            Ok(_) if output.len() == output.capacity() => output.reserve(output.capacity()),
+            _ => return None,
+        }
+    }
+}
+
+fn zlib_gunzip(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .read_to_end(&mut output)
+        .unwrap();
+    output
+}
+
+fn wrap_gzip(header: &[u8], body: &[u8], decompressed: &[u8]) -> Vec<u8> {
+    let mut crc = Crc::new();
+    crc.update(decompressed);
+
+    let mut member = header.to_vec();
+    member.extend_from_slice(body);
+    member.extend_from_slice(&crc.sum().to_le_bytes());
+    member.extend_from_slice(&(decompressed.len() as u32).to_le_bytes());
+    member
+}
+
+fn test_payloads() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("empty", Vec::new()),
+        ("one byte", vec![b'x']),
+        ("window sized", random_bytes(32768, 1)),
+        ("random", random_bytes(300_000, 2)),
+        ("english", english_text(300_000)),
+        ("dna", dna_sequence(300_000)),
+        ("repeated", repeated_pattern(300_000)),
+    ]
+}
+
+#[test]
+fn matches_zlib_on_generated_streams() {
+    for (name, payload) in test_payloads() {
+        for level in [1, 6, 9] {
+            let compressed = gzip(&payload, level);
+            let reference = zlib_gunzip(&compressed);
+            assert_eq!(
+                reference, payload,
+                "zlib mismatch on {} level {}",
+                name, level
+            );
+
+            let decompressed = crate_gunzip(&compressed)
+                .unwrap_or_else(|err| panic!("{} level {} failed: {:?}", name, level, err));
+            assert!(
+                decompressed == reference,
+                "{} level {} differs",
+                name,
+                level
+            );
+        }
+    }
+}
+
+#[test]
+fn matches_zlib_on_mutated_streams() {
+    let mut rng = XorShift(0xf022);
+    let mut accepted_by_zlib = 0;
+
+    for (_, payload) in test_payloads() {
+        let compressed = gzip(&payload[..payload.len().min(20_000)], 6);
+        let header = &compressed[..GZIP_HEADER_SIZE];
+        let body = &compressed[GZIP_HEADER_SIZE..compressed.len() - GZIP_FOOTER_SIZE];
+
+        for _ in 0..200 {
+            let mut mutated = body.to_vec();
+            if mutated.is_empty() {
+                break;
+            }
+            for _ in 0..(1 + rng.next() % 3) {
+                let pos = rng.next() as usize % mutated.len();
+                mutated[pos] ^= 1 << (rng.next() % 8);
+            }
+
+            /* Only streams zlib accepts are interesting; rewrap them with a
+             * matching trailer so that only the DEFLATE data is compared */
+            let Some((reference, consumed)) = zlib_inflate_raw(&mutated) else {
+                continue;
+            };
+            accepted_by_zlib += 1;
+            let member = wrap_gzip(header, &mutated[..consumed], &reference);
+
+            /* Rejecting a stream zlib accepts is tolerated, wrong output is not */
+            if let Ok(decompressed) = crate_gunzip(&member) {
+                assert!(
+                    decompressed == reference,
+                    "wrong output on a mutated stream"
+                );
+            }
+        }
+    }
+
+    assert!(accepted_by_zlib > 0);
+}